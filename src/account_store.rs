@@ -1,44 +1,353 @@
 use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 
-use crate::types::{Account, Amount, ClientId};
+use crate::types::{Account, Amount, ClientId, StoreHasher, SubMinorUnitPolicy, TransactionId};
 
 /// Store account information to settle transactions
 pub trait AccountStore {
     /// Process a balance change, positive amounts mean deposits, negative mean withdrawals
     /// Calls to this functions will fail for locked accounts.
+    /// Withdrawals can only use the available funds, held funds are never withdrawable.
     fn add_to_balance(&mut self, client: ClientId, amount: Amount) -> Result<()>;
 
-    /// Hold the given (positive) amount due to a dispute (resolved by later transactions)
+    /// Process several balance changes for the same client (see `add_to_balance`)
+    /// Either all changes are applied or, if any of them fails, none at all.
+    fn apply_batch(&mut self, client: ClientId, deltas: &[Amount]) -> Result<()>;
+
+    /// Hold the given (positive) amount due to a dispute of `transaction` (resolved by later
+    /// transactions)
+    /// This function still works for locked accounts.
+    /// The full amount is held, even if this leaves the available funds negative.
+    /// Returns the amount that was actually held.
+    fn hold_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount>;
+
+    /// Release the given (positive) amount held for `transaction` into the available funds
+    /// Funds held for other transactions are never touched.
     /// This function still works for locked accounts.
-    fn hold_amount(&mut self, client: ClientId, amount: Amount) -> Result<()>;
+    /// Exactly the given amount is released, so that `available + held` stays unchanged. Releasing
+    /// more than is held for `transaction` fails.
+    /// Returns the amount that was actually released.
+    fn release_held_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount>;
 
-    /// Release the given (positive) amount into the available funds
+    /// Withdraw the given (positive) amount held for `transaction` and lock the account
+    /// Funds held for other transactions are never touched.
     /// This function still works for locked accounts.
-    fn release_held_amount(&mut self, client: ClientId, amount: Amount) -> Result<()>;
+    /// Exactly the given amount is charged back. Charging back more than is held for
+    /// `transaction` fails.
+    /// Returns the amount that was actually charged back. After a partial chargeback, the rest
+    /// stays held for `transaction` and can still be released.
+    fn charge_back_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount>;
 
-    /// Withdraw the given (positive) amount from the held funds and lock the account
+    /// Hold the given (positive) amount of a disputed withdrawal `transaction`, the money is
+    /// provisionally returned to the client (but not available)
     /// This function still works for locked accounts.
-    fn charge_back_amount(&mut self, client: ClientId, amount: Amount) -> Result<()>;
+    fn hold_reversed_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount>;
+
+    /// Drop the given (positive) amount held for a disputed withdrawal `transaction`, as the
+    /// withdrawal stands after all
+    /// Returns the amount that was actually dropped (limited by the amount held).
+    fn discard_reversed_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount>;
+
+    /// Make the given (positive) amount held for a disputed withdrawal `transaction` available
+    /// again, reversing the withdrawal, and lock the account like any chargeback
+    /// Returns the amount that was actually reversed (limited by the amount held).
+    fn charge_back_reversed_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount>;
+
+    /// Sum of the held funds over all accounts
+    fn total_held(&self) -> Amount;
+
+    /// Whether an account exists for the client
+    fn has_account(&self, client: ClientId) -> bool;
+
+    /// Whether the client's account exists and is locked
+    fn is_locked(&self, client: ClientId) -> bool;
+
+    /// The chargeback that locked the client's account (`None` if it is not locked or was already
+    /// locked when it was opened)
+    fn locked_by(&self, client: ClientId) -> Option<TransactionId>;
+
+    /// The funds currently held for `transaction` (`None` if nothing is held for it at all)
+    fn held_for(&self, client: ClientId, transaction: TransactionId) -> Option<Amount>;
+
+    /// Number of accounts
+    fn len(&self) -> usize;
+
+    /// Whether there are no accounts at all
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct AccountData {
     pub available: Amount,
+
+    /// Sum of all amounts in `held_by_transaction` plus any held opening balance
     pub held: Amount,
     pub locked: bool,
+
+    /// The chargeback that locked the account (if it was locked by one in this run)
+    locked_by: Option<TransactionId>,
+
+    /// The held amount attributed to each disputed transaction
+    held_by_transaction: HashMap<TransactionId, Amount>,
+}
+
+impl AccountData {
+    fn new(available: Amount) -> Self {
+        Self {
+            available,
+            held: Amount::default(),
+            locked: false,
+            locked_by: None,
+            held_by_transaction: HashMap::new(),
+        }
+    }
+
+    /// Lock the account due to the chargeback `transaction` (unless it is locked already)
+    fn lock(&mut self, transaction: TransactionId) {
+        if !self.locked {
+            self.locked = true;
+            self.locked_by = Some(transaction);
+        }
+    }
+
+    /// Remove exactly `amount` from the funds held for `transaction`
+    /// Taking more than the funds held for `transaction` fails, as the rest would come out of the
+    /// funds held for other transactions.
+    fn take_held(&mut self, transaction: TransactionId, amount: Amount) -> Result<Amount> {
+        let held = self
+            .held_by_transaction
+            .get_mut(&transaction)
+            .ok_or_else(|| anyhow!("No funds held for transaction (tx = {})", transaction))?;
+        if amount > *held {
+            return Err(anyhow!(
+                "Only {} of {} held for transaction (tx = {})",
+                held,
+                amount,
+                transaction
+            ));
+        }
+
+        *held -= amount;
+        if *held == Amount::default() {
+            self.held_by_transaction.remove(&transaction);
+        }
+        self.held -= amount;
+        Ok(amount)
+    }
+
+    /// Remove up to `amount` from the funds held for `transaction`
+    fn take_held_up_to(&mut self, transaction: TransactionId, amount: Amount) -> Result<Amount> {
+        let held = self
+            .held_by_transaction
+            .get_mut(&transaction)
+            .ok_or_else(|| anyhow!("No funds held for transaction (tx = {})", transaction))?;
+
+        let amount_to_be_taken = (*held).min(amount);
+        *held -= amount_to_be_taken;
+        if *held == Amount::default() {
+            self.held_by_transaction.remove(&transaction);
+        }
+        self.held -= amount_to_be_taken;
+        Ok(amount_to_be_taken)
+    }
+}
+
+/// Smallest unit of an amount that can be released from the held funds
+/// Releases that are not a multiple of the unit fail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Granularity {
+    unit: Amount,
+}
+
+impl Granularity {
+    /// Only positive units are valid (nothing could be released in multiples of zero)
+    pub fn new(unit: Amount) -> Result<Self> {
+        if unit <= Amount::default() {
+            return Err(anyhow!("Release granularity must be positive ({})", unit));
+        }
+        Ok(Self { unit })
+    }
+}
+
+/// The state of a single account (or its absence) with respect to one transaction to restore it
+/// later, capturing it is O(1) no matter how many disputes of the client are open
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountSnapshot(Option<AccountState>);
+
+/// The parts of an account a single transaction can change
+#[derive(Debug, Clone, PartialEq)]
+struct AccountState {
+    available: Amount,
+    held: Amount,
+    locked: bool,
+    locked_by: Option<TransactionId>,
+    held_for_transaction: Option<Amount>,
 }
 
 /// A simple RAM-backed account store using a standard Rust `HashMap`
+#[derive(Clone)]
 pub struct HashMapAccountStore {
-    data_store: HashMap<ClientId, AccountData>,
+    data_store: HashMap<ClientId, AccountData, StoreHasher>,
+    release_granularity: Option<Granularity>,
+    hold_rounding: SubMinorUnitPolicy,
+    lock_on_chargeback: bool,
 }
 
 impl HashMapAccountStore {
     pub fn new() -> Self {
         Self {
-            data_store: HashMap::new(),
+            data_store: HashMap::default(),
+            release_granularity: None,
+            hold_rounding: SubMinorUnitPolicy::Keep,
+            lock_on_chargeback: true,
+        }
+    }
+
+    /// Create a store that only releases held funds in multiples of `granularity.unit`
+    pub fn with_release_granularity(granularity: Granularity) -> Self {
+        Self {
+            data_store: HashMap::default(),
+            release_granularity: Some(granularity),
+            hold_rounding: SubMinorUnitPolicy::Keep,
+            lock_on_chargeback: true,
+        }
+    }
+
+    /// Apply `policy` to every amount before it is held, so that held funds have the same scale
+    /// as the deposits they come from
+    pub fn set_hold_rounding(&mut self, policy: SubMinorUnitPolicy) {
+        self.hold_rounding = policy;
+    }
+
+    /// Whether a chargeback locks the account (the default), otherwise it only takes the held funds
+    pub fn set_lock_on_chargeback(&mut self, lock: bool) {
+        self.lock_on_chargeback = lock;
+    }
+
+    /// Create an account with the given (opening) balance
+    /// Held funds are not attributed to any transaction, so they can never be released.
+    pub fn open_account(&mut self, account: Account) -> Result<()> {
+        if self.data_store.contains_key(&account.client) {
+            return Err(anyhow!(
+                "Account already exists (client = {})",
+                account.client
+            ));
+        }
+
+        let mut data = AccountData::new(account.available);
+        data.held = account.held;
+        data.locked = account.locked;
+        self.data_store.insert(account.client, data);
+        Ok(())
+    }
+
+    /// All accounts (in no particular order)
+    pub fn accounts(&self) -> impl Iterator<Item = Account> + '_ {
+        self.data_store.iter().map(|(client, data)| Account {
+            client: *client,
+            available: data.available,
+            held: data.held,
+            locked: data.locked,
+        })
+    }
+
+    /// Check whether `amount` can be released for `transaction` without changing anything
+    /// Only fails for amounts that are not a multiple of the release granularity.
+    pub fn check_release(&self, transaction: TransactionId, amount: Amount) -> Result<()> {
+        if let Some(granularity) = &self.release_granularity {
+            if amount % granularity.unit != Amount::default() {
+                return Err(anyhow!(
+                    "Release is not a multiple of {} (tx = {})",
+                    granularity.unit,
+                    transaction
+                ));
+            }
         }
+        Ok(())
+    }
+
+    /// Capture the state of the client's account as far as `transaction` can change it
+    pub fn snapshot(&self, client: ClientId, transaction: TransactionId) -> AccountSnapshot {
+        AccountSnapshot(self.data_store.get(&client).map(|data| AccountState {
+            available: data.available,
+            held: data.held,
+            locked: data.locked,
+            locked_by: data.locked_by,
+            held_for_transaction: data.held_by_transaction.get(&transaction).copied(),
+        }))
+    }
+
+    /// Put the client's account back into the state captured for `transaction`
+    pub fn restore(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        snapshot: AccountSnapshot,
+    ) {
+        let state = match snapshot.0 {
+            Some(state) => state,
+            None => {
+                self.data_store.remove(&client);
+                return;
+            }
+        };
+
+        let data = self
+            .data_store
+            .entry(client)
+            .or_insert_with(|| AccountData::new(Amount::default()));
+        data.available = state.available;
+        data.held = state.held;
+        data.locked = state.locked;
+        data.locked_by = state.locked_by;
+        match state.held_for_transaction {
+            Some(held) => data.held_by_transaction.insert(transaction, held),
+            None => data.held_by_transaction.remove(&transaction),
+        };
+    }
+
+    /// Locked clients without any funds left (candidates for closure review, sorted by ID)
+    pub fn closed_candidates(&self) -> Vec<ClientId> {
+        let mut clients: Vec<_> = self
+            .data_store
+            .iter()
+            .filter(|(_, data)| data.locked && data.available + data.held == Amount::default())
+            .map(|(client, _)| *client)
+            .collect();
+        clients.sort_unstable();
+        clients
     }
 }
 
@@ -73,8 +382,9 @@ impl AccountStore for HashMapAccountStore {
                 ));
             }
 
+            // Deposits are always accepted, even if a dispute left the available funds negative
             let new_amount = data.available + amount;
-            if new_amount.is_sign_negative() {
+            if amount.is_sign_negative() && new_amount.is_sign_negative() {
                 return Err(anyhow!(
                     "Transaction would cause negative balance (client = {})",
                     client
@@ -89,34 +399,79 @@ impl AccountStore for HashMapAccountStore {
                 ));
             }
 
-            self.data_store.insert(
-                client,
-                AccountData {
-                    available: amount,
-                    held: Amount::ZERO,
-                    locked: false,
-                },
-            );
+            self.data_store.insert(client, AccountData::new(amount));
+        }
+        Ok(())
+    }
+
+    fn apply_batch(&mut self, client: ClientId, deltas: &[Amount]) -> Result<()> {
+        let data = self.data_store.get_mut(&client);
+        if data.as_ref().is_some_and(|data| data.locked) {
+            return Err(anyhow!(
+                "Cannot change balance of locked account (client = {})",
+                client
+            ));
+        }
+
+        // Work on a copy so that nothing changes should any of the deltas fail
+        let mut available = data
+            .as_ref()
+            .map_or(Amount::default(), |data| data.available);
+        for delta in deltas {
+            available += *delta;
+            if delta.is_sign_negative() && available.is_sign_negative() {
+                return Err(anyhow!(
+                    "Batch would cause negative balance (client = {})",
+                    client
+                ));
+            }
+        }
+
+        match data {
+            Some(data) => data.available = available,
+            None if !deltas.is_empty() => {
+                self.data_store.insert(client, AccountData::new(available));
+            }
+            None => {}
         }
         Ok(())
     }
 
-    fn hold_amount(&mut self, client: ClientId, amount: Amount) -> Result<()> {
+    fn hold_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount> {
         if amount.is_sign_negative() {
             return Err(anyhow!("Cannot hold negative amount (client = {})", client));
         }
+        let amount = self.hold_rounding.apply(amount, transaction)?;
 
         if let Some(data) = self.data_store.get_mut(&client) {
-            let amount_to_be_held = data.available.min(amount);
-            data.available -= amount_to_be_held;
-            data.held += amount_to_be_held;
+            if data.held_by_transaction.contains_key(&transaction) {
+                return Err(anyhow!(
+                    "Funds already held for transaction (tx = {})",
+                    transaction
+                ));
+            }
+
+            // the full amount is held, even if the available funds become negative
+            data.available -= amount;
+            data.held += amount;
+            data.held_by_transaction.insert(transaction, amount);
+            Ok(amount)
         } else {
-            return Err(anyhow!("Client does not exist (client = {})", client));
+            Err(anyhow!("Client does not exist (client = {})", client))
         }
-        Ok(())
     }
 
-    fn release_held_amount(&mut self, client: ClientId, amount: Amount) -> Result<()> {
+    fn release_held_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount> {
         if amount.is_sign_negative() {
             return Err(anyhow!(
                 "Cannot release negative amount (client = {})",
@@ -124,17 +479,23 @@ impl AccountStore for HashMapAccountStore {
             ));
         }
 
+        self.check_release(transaction, amount)?;
+
         if let Some(data) = self.data_store.get_mut(&client) {
-            let amount_to_be_released = data.held.min(amount);
+            let amount_to_be_released = data.take_held(transaction, amount)?;
             data.available += amount_to_be_released;
-            data.held -= amount_to_be_released;
+            Ok(amount_to_be_released)
         } else {
-            return Err(anyhow!("Client does not exist (client = {})", client));
+            Err(anyhow!("Client does not exist (client = {})", client))
         }
-        Ok(())
     }
 
-    fn charge_back_amount(&mut self, client: ClientId, amount: Amount) -> Result<()> {
+    fn charge_back_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount> {
         if amount.is_sign_negative() {
             return Err(anyhow!(
                 "Cannot charge back negative amount (client = {})",
@@ -143,20 +504,118 @@ impl AccountStore for HashMapAccountStore {
         }
 
         if let Some(data) = self.data_store.get_mut(&client) {
-            let amount_to_be_charged = data.held.min(amount);
-            data.held -= amount_to_be_charged;
-            data.locked = true;
+            let amount_to_be_charged = data.take_held(transaction, amount)?;
+            if self.lock_on_chargeback {
+                data.lock(transaction);
+            }
+            Ok(amount_to_be_charged)
         } else {
-            return Err(anyhow!("Client does not exist (client = {})", client));
+            Err(anyhow!("Client does not exist (client = {})", client))
         }
-        Ok(())
+    }
+
+    fn hold_reversed_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount> {
+        if amount.is_sign_negative() {
+            return Err(anyhow!("Cannot hold negative amount (client = {})", client));
+        }
+        let amount = self.hold_rounding.apply(amount, transaction)?;
+
+        if let Some(data) = self.data_store.get_mut(&client) {
+            if data.held_by_transaction.contains_key(&transaction) {
+                return Err(anyhow!(
+                    "Funds already held for transaction (tx = {})",
+                    transaction
+                ));
+            }
+
+            data.held += amount;
+            data.held_by_transaction.insert(transaction, amount);
+            Ok(amount)
+        } else {
+            Err(anyhow!("Client does not exist (client = {})", client))
+        }
+    }
+
+    fn discard_reversed_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount> {
+        if amount.is_sign_negative() {
+            return Err(anyhow!("Cannot drop negative amount (client = {})", client));
+        }
+
+        if let Some(data) = self.data_store.get_mut(&client) {
+            data.take_held_up_to(transaction, amount)
+        } else {
+            Err(anyhow!("Client does not exist (client = {})", client))
+        }
+    }
+
+    fn charge_back_reversed_amount(
+        &mut self,
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Amount> {
+        if amount.is_sign_negative() {
+            return Err(anyhow!(
+                "Cannot charge back negative amount (client = {})",
+                client
+            ));
+        }
+
+        if let Some(data) = self.data_store.get_mut(&client) {
+            let amount_to_be_reversed = data.take_held_up_to(transaction, amount)?;
+            data.available += amount_to_be_reversed;
+            if self.lock_on_chargeback {
+                data.lock(transaction);
+            }
+            Ok(amount_to_be_reversed)
+        } else {
+            Err(anyhow!("Client does not exist (client = {})", client))
+        }
+    }
+
+    fn total_held(&self) -> Amount {
+        self.data_store.values().map(|data| data.held).sum()
+    }
+
+    fn has_account(&self, client: ClientId) -> bool {
+        self.data_store.contains_key(&client)
+    }
+
+    fn is_locked(&self, client: ClientId) -> bool {
+        self.data_store.get(&client).is_some_and(|data| data.locked)
+    }
+
+    fn locked_by(&self, client: ClientId) -> Option<TransactionId> {
+        self.data_store.get(&client).and_then(|data| data.locked_by)
+    }
+
+    fn held_for(&self, client: ClientId, transaction: TransactionId) -> Option<Amount> {
+        self.data_store
+            .get(&client)
+            .and_then(|data| data.held_by_transaction.get(&transaction))
+            .copied()
+    }
+
+    fn len(&self) -> usize {
+        self.data_store.len()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rust_decimal_macros::dec;
+    use crate::test_support::amount;
+    use crate::test_support::tx;
 
     #[test]
     fn empty_store() {
@@ -168,15 +627,15 @@ mod tests {
     fn single_positive_balance_change() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(1.0)).unwrap();
+        store.add_to_balance(0, amount!(1.0)).unwrap();
 
         let entries: Vec<_> = store.into_iter().collect();
         assert_eq!(
             entries,
             vec![Account {
                 client: 0,
-                available: dec!(1.0),
-                held: Amount::ZERO,
+                available: amount!(1.0),
+                held: Amount::default(),
                 locked: false,
             }]
         );
@@ -186,7 +645,7 @@ mod tests {
     fn single_negative_balance_change() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(-1.0)).unwrap_err();
+        store.add_to_balance(0, amount!(-1.0)).unwrap_err();
         assert_eq!(store.into_iter().count(), 0);
     }
 
@@ -194,9 +653,13 @@ mod tests {
     fn hold_release_charge_from_non_existing_account() {
         let mut store = HashMapAccountStore::new();
 
-        store.hold_amount(0, dec!(1.0)).unwrap_err();
-        store.release_held_amount(0, dec!(1.0)).unwrap_err();
-        store.charge_back_amount(0, dec!(1.0)).unwrap_err();
+        store.hold_amount(0, tx(0), amount!(1.0)).unwrap_err();
+        store
+            .release_held_amount(0, tx(0), amount!(1.0))
+            .unwrap_err();
+        store
+            .charge_back_amount(0, tx(0), amount!(1.0))
+            .unwrap_err();
         assert_eq!(store.into_iter().count(), 0);
     }
 
@@ -204,26 +667,30 @@ mod tests {
     fn hold_release_negative_amount() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(2.0)).unwrap();
-        store.hold_amount(0, dec!(-1.0)).unwrap_err();
-        store.release_held_amount(0, dec!(-1.0)).unwrap_err();
-        store.charge_back_amount(0, dec!(-1.0)).unwrap_err();
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store.hold_amount(0, tx(0), amount!(-1.0)).unwrap_err();
+        store
+            .release_held_amount(0, tx(0), amount!(-1.0))
+            .unwrap_err();
+        store
+            .charge_back_amount(0, tx(0), amount!(-1.0))
+            .unwrap_err();
     }
 
     #[test]
     fn hold_partial_amount() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(2.0)).unwrap();
-        store.hold_amount(0, dec!(1.0)).unwrap();
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store.hold_amount(0, tx(0), amount!(1.0)).unwrap();
 
         let entries: Vec<_> = store.into_iter().collect();
         assert_eq!(
             entries,
             vec![Account {
                 client: 0,
-                available: dec!(1.0),
-                held: dec!(1.0),
+                available: amount!(1.0),
+                held: amount!(1.0),
                 locked: false,
             }]
         );
@@ -233,16 +700,19 @@ mod tests {
     fn hold_more_than_available() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(2.0)).unwrap();
-        store.hold_amount(0, dec!(5.0)).unwrap();
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        assert_eq!(
+            store.hold_amount(0, tx(0), amount!(5.0)).unwrap(),
+            amount!(5.0)
+        );
 
         let entries: Vec<_> = store.into_iter().collect();
         assert_eq!(
             entries,
             vec![Account {
                 client: 0,
-                available: Amount::ZERO,
-                held: dec!(2.0),
+                available: amount!(-3.0),
+                held: amount!(5.0),
                 locked: false,
             }]
         );
@@ -252,17 +722,17 @@ mod tests {
     fn release_partial_amount() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(2.0)).unwrap();
-        store.hold_amount(0, dec!(1.0)).unwrap();
-        store.release_held_amount(0, dec!(0.5)).unwrap();
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store.hold_amount(0, tx(0), amount!(1.0)).unwrap();
+        store.release_held_amount(0, tx(0), amount!(0.5)).unwrap();
 
         let entries: Vec<_> = store.into_iter().collect();
         assert_eq!(
             entries,
             vec![Account {
                 client: 0,
-                available: dec!(1.5),
-                held: dec!(0.5),
+                available: amount!(1.5),
+                held: amount!(0.5),
                 locked: false,
             }]
         );
@@ -272,17 +742,24 @@ mod tests {
     fn release_more_then_held() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(2.0)).unwrap();
-        store.hold_amount(0, dec!(1.0)).unwrap();
-        store.release_held_amount(0, dec!(5.0)).unwrap();
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store.hold_amount(0, tx(0), amount!(1.0)).unwrap();
+        store.hold_amount(0, tx(1), amount!(0.5)).unwrap();
+
+        // the excess would come out of the funds held for the other transaction
+        store
+            .release_held_amount(0, tx(0), amount!(1.5))
+            .unwrap_err();
+        assert_eq!(store.held_for(0, tx(0)), Some(amount!(1.0)));
+        assert_eq!(store.held_for(0, tx(1)), Some(amount!(0.5)));
 
         let entries: Vec<_> = store.into_iter().collect();
         assert_eq!(
             entries,
             vec![Account {
                 client: 0,
-                available: dec!(2.0),
-                held: Amount::ZERO,
+                available: amount!(0.5),
+                held: amount!(1.5),
                 locked: false,
             }]
         );
@@ -292,55 +769,328 @@ mod tests {
     fn charge_back_partial_amount() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(2.0)).unwrap();
-        store.hold_amount(0, dec!(1.0)).unwrap();
-        store.charge_back_amount(0, dec!(0.5)).unwrap();
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store.hold_amount(0, tx(0), amount!(1.0)).unwrap();
+        store.charge_back_amount(0, tx(0), amount!(0.5)).unwrap();
 
         let entries: Vec<_> = store.into_iter().collect();
         assert_eq!(
             entries,
             vec![Account {
                 client: 0,
-                available: dec!(1.0),
-                held: dec!(0.5),
+                available: amount!(1.0),
+                held: amount!(0.5),
                 locked: true,
             }]
         );
     }
 
     #[test]
-    fn charge_back_more_then_held() {
+    fn deposit_after_partial_charge_back() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(2.0)).unwrap();
-        store.hold_amount(0, dec!(1.0)).unwrap();
-        store.charge_back_amount(0, dec!(5.0)).unwrap();
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store.hold_amount(0, tx(0), amount!(1.0)).unwrap();
+        store.charge_back_amount(0, tx(0), amount!(0.5)).unwrap();
+
+        // the account is locked by the chargeback, the residual held funds are not touched
+        store.add_to_balance(0, amount!(3.0)).unwrap_err();
+        assert_eq!(store.held_for(0, tx(0)), Some(amount!(0.5)));
+        assert_eq!(store.total_held(), amount!(0.5));
+
+        assert_eq!(
+            store.release_held_amount(0, tx(0), amount!(0.5)).unwrap(),
+            amount!(0.5)
+        );
+        assert_eq!(store.held_for(0, tx(0)), None);
 
         let entries: Vec<_> = store.into_iter().collect();
         assert_eq!(
             entries,
             vec![Account {
                 client: 0,
-                available: dec!(1.0),
-                held: Amount::ZERO,
+                available: amount!(1.5),
+                held: amount!(0.0),
                 locked: true,
             }]
         );
     }
 
+    #[test]
+    fn charge_back_more_then_held() {
+        let mut store = HashMapAccountStore::new();
+
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store.hold_amount(0, tx(0), amount!(1.0)).unwrap();
+        store.hold_amount(0, tx(1), amount!(0.5)).unwrap();
+
+        // nothing is taken and the account stays unlocked
+        store
+            .charge_back_amount(0, tx(0), amount!(1.5))
+            .unwrap_err();
+        assert_eq!(store.held_for(0, tx(0)), Some(amount!(1.0)));
+        assert_eq!(store.held_for(0, tx(1)), Some(amount!(0.5)));
+
+        let entries: Vec<_> = store.into_iter().collect();
+        assert_eq!(
+            entries,
+            vec![Account {
+                client: 0,
+                available: amount!(0.5),
+                held: amount!(1.5),
+                locked: false,
+            }]
+        );
+    }
+
     #[test]
     fn try_modifying_locked_balance() {
         let mut store = HashMapAccountStore::new();
 
-        store.add_to_balance(0, dec!(2.0)).unwrap();
-        store.hold_amount(0, dec!(1.0)).unwrap();
-        store.charge_back_amount(0, dec!(5.0)).unwrap();
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store.hold_amount(0, tx(0), amount!(1.0)).unwrap();
+        store.charge_back_amount(0, tx(0), amount!(1.0)).unwrap();
 
         // locked accounts can still increase the held amount
-        store.hold_amount(0, dec!(1.0)).unwrap();
+        store.hold_amount(0, tx(1), amount!(1.0)).unwrap();
 
         // locked accounts cannot have balance changes
-        store.add_to_balance(0, dec!(2.0)).unwrap_err();
-        store.add_to_balance(0, dec!(-2.0)).unwrap_err();
+        store.add_to_balance(0, amount!(2.0)).unwrap_err();
+        store.add_to_balance(0, amount!(-2.0)).unwrap_err();
+    }
+
+    #[test]
+    fn apply_batch() {
+        let mut store = HashMapAccountStore::new();
+
+        store
+            .apply_batch(0, &[amount!(2.0), amount!(-1.5), amount!(3.0)])
+            .unwrap();
+
+        let entries: Vec<_> = store.into_iter().collect();
+        assert_eq!(
+            entries,
+            vec![Account {
+                client: 0,
+                available: amount!(3.5),
+                held: Amount::default(),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn apply_batch_is_atomic() {
+        let mut store = HashMapAccountStore::new();
+
+        // a failing batch does not create the account
+        store
+            .apply_batch(0, &[amount!(1.0), amount!(-2.0)])
+            .unwrap_err();
+        assert_eq!(store.into_iter().count(), 0);
+
+        // the intermediate overdraw fails the batch even though the final sum is positive
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store
+            .apply_batch(0, &[amount!(1.0), amount!(-4.0), amount!(5.0)])
+            .unwrap_err();
+
+        let entries: Vec<_> = store.into_iter().collect();
+        assert_eq!(
+            entries,
+            vec![Account {
+                client: 0,
+                available: amount!(2.0),
+                held: Amount::default(),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn total_held() {
+        let mut store = HashMapAccountStore::new();
+        assert_eq!(store.total_held(), Amount::default());
+
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+        store.add_to_balance(1, amount!(3.0)).unwrap();
+        store.add_to_balance(2, amount!(4.0)).unwrap();
+        store.hold_amount(0, tx(0), amount!(1.5)).unwrap();
+        store.hold_amount(2, tx(0), amount!(4.0)).unwrap();
+
+        assert_eq!(store.total_held(), amount!(5.5));
+    }
+
+    #[test]
+    fn overlapping_disputes_resolved_independently() {
+        let mut store = HashMapAccountStore::new();
+
+        store.add_to_balance(0, amount!(10.0)).unwrap();
+        store.add_to_balance(0, amount!(-8.0)).unwrap();
+
+        // only 2.0 are available, but both disputes hold their full amount
+        assert_eq!(
+            store.hold_amount(0, tx(1), amount!(5.0)).unwrap(),
+            amount!(5.0)
+        );
+        assert_eq!(
+            store.hold_amount(0, tx(2), amount!(3.0)).unwrap(),
+            amount!(3.0)
+        );
+        store.hold_amount(0, tx(1), amount!(5.0)).unwrap_err(); // already held
+
+        // resolving the second dispute must not release the funds of the first
+        assert_eq!(
+            store.release_held_amount(0, tx(2), amount!(3.0)).unwrap(),
+            amount!(3.0)
+        );
+        assert_eq!(store.total_held(), amount!(5.0));
+
+        assert_eq!(
+            store.release_held_amount(0, tx(1), amount!(5.0)).unwrap(),
+            amount!(5.0)
+        );
+        store
+            .release_held_amount(0, tx(1), amount!(5.0))
+            .unwrap_err(); // nothing held anymore
+
+        let entries: Vec<_> = store.into_iter().collect();
+        assert_eq!(
+            entries,
+            vec![Account {
+                client: 0,
+                available: amount!(2.0),
+                held: Amount::default(),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn overlapping_disputes_charged_back_independently() {
+        let mut store = HashMapAccountStore::new();
+
+        store.add_to_balance(0, amount!(10.0)).unwrap();
+        store.hold_amount(0, tx(1), amount!(3.0)).unwrap();
+        store.hold_amount(0, tx(2), amount!(4.0)).unwrap();
+
+        assert_eq!(
+            store.charge_back_amount(0, tx(2), amount!(4.0)).unwrap(),
+            amount!(4.0)
+        );
+
+        let entries: Vec<_> = store.into_iter().collect();
+        assert_eq!(
+            entries,
+            vec![Account {
+                client: 0,
+                available: amount!(3.0),
+                held: amount!(3.0),
+                locked: true,
+            }]
+        );
+
+        assert_eq!(
+            store.release_held_amount(0, tx(1), amount!(3.0)).unwrap(),
+            amount!(3.0)
+        );
+
+        let entries: Vec<_> = store.into_iter().collect();
+        assert_eq!(
+            entries,
+            vec![Account {
+                client: 0,
+                available: amount!(6.0),
+                held: Amount::default(),
+                locked: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn hold_rounding() {
+        let mut store = HashMapAccountStore::new();
+        store.set_hold_rounding(SubMinorUnitPolicy::Round);
+        store.add_to_balance(0, amount!(2.0)).unwrap();
+
+        assert_eq!(
+            store.hold_amount(0, tx(1), amount!(1.00005)).unwrap(),
+            amount!(1.0001)
+        );
+        assert_eq!(store.held_for(0, tx(1)), Some(amount!(1.0001)));
+
+        store.set_hold_rounding(SubMinorUnitPolicy::Reject);
+        assert!(store.hold_amount(0, tx(2), amount!(0.00001)).is_err());
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn release_below_granularity_rejected() {
+        let mut store =
+            HashMapAccountStore::with_release_granularity(Granularity::new(amount!(0.01)).unwrap());
+        store.add_to_balance(0, amount!(1.0)).unwrap();
+        store.hold_amount(0, tx(1), amount!(1.0)).unwrap();
+
+        assert!(store.release_held_amount(0, tx(1), amount!(0.005)).is_err());
+        assert_eq!(
+            store.release_held_amount(0, tx(1), amount!(0.5)).unwrap(),
+            amount!(0.5)
+        );
+
+        let entries: Vec<_> = store.into_iter().collect();
+        assert_eq!(entries[0].available, amount!(0.5));
+        assert_eq!(entries[0].held, amount!(0.5));
+    }
+
+    #[test]
+    fn zero_granularity() {
+        assert!(Granularity::new(amount!(0)).is_err());
+        assert!(Granularity::new(amount!(-0.01)).is_err());
+    }
+
+    #[cfg(feature = "deterministic")]
+    #[test]
+    fn deterministic_order() {
+        let order = || {
+            let mut store = HashMapAccountStore::new();
+            for client in 0..100 {
+                store.add_to_balance(client, amount!(1.0)).unwrap();
+            }
+            store
+                .into_iter()
+                .map(|account| account.client)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(), order());
+    }
+
+    #[test]
+    fn closed_candidates() {
+        let mut store = HashMapAccountStore::new();
+
+        store.add_to_balance(0, amount!(1.0)).unwrap();
+        store.hold_amount(0, tx(1), amount!(1.0)).unwrap();
+        store.charge_back_amount(0, tx(1), amount!(1.0)).unwrap();
+
+        store.add_to_balance(1, amount!(1.0)).unwrap();
+        store.add_to_balance(1, amount!(2.0)).unwrap();
+        store.hold_amount(1, tx(2), amount!(1.0)).unwrap();
+        store.charge_back_amount(1, tx(2), amount!(1.0)).unwrap();
+
+        store.add_to_balance(2, amount!(0.0)).unwrap();
+
+        assert_eq!(store.closed_candidates(), vec![0]);
+    }
+
+    #[test]
+    fn len() {
+        let mut store = HashMapAccountStore::new();
+        assert!(store.is_empty());
+
+        store.add_to_balance(0, amount!(1.0)).unwrap();
+        store.add_to_balance(1, amount!(1.0)).unwrap();
+        store.add_to_balance(0, amount!(1.0)).unwrap();
+        store.add_to_balance(2, amount!(-1.0)).unwrap_err();
+        assert_eq!(store.len(), 2);
     }
 }
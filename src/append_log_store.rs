@@ -0,0 +1,470 @@
+use anyhow::{anyhow, Context, Result};
+use std::io::{BufRead, Write};
+
+use crate::transaction_store::{
+    HashMapTransactionStore, TransactionSnapshot, TransactionStore, UndisputeOutcome,
+};
+use crate::types::{
+    DisputableTransaction, DisputeOutcome, DisputedTransactionRecord, MonetaryTransactionRecord,
+    StoredTransaction, TransactionId,
+};
+
+/// A transaction store that records every change in an append-only log file
+///
+/// The log has one comma-separated line per event, e.g. `deposit,<client>,<tx>,<amount>` or
+/// `dispute,<client>,<tx>` (withdrawals are only logged with client and amount if they are stored
+/// as disputable transactions). Only successful changes are logged. Opening an existing log replays it
+/// to rebuild the in-memory index that all lookups are answered from. Every event is written
+/// immediately (unbuffered) so that it survives a crash of the process, unless the store is opened
+/// with `open_batched`.
+pub struct AppendLogTransactionStore {
+    index: HashMapTransactionStore,
+    log: std::fs::File,
+
+    /// Events not written yet, `None` if every event is written immediately
+    pending: Option<String>,
+}
+
+impl AppendLogTransactionStore {
+    /// Open (or create) the log at `path` and replay all events already in it
+    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut index = HashMapTransactionStore::new();
+
+        if path.exists() {
+            let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+            for (number, line) in reader.lines().enumerate() {
+                replay(&mut index, &line?)
+                    .with_context(|| format!("{} line {}", path.display(), number + 1))?;
+            }
+        }
+
+        let log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            index,
+            log,
+            pending: None,
+        })
+    }
+
+    /// Like `open`, but only write the events on `commit` (or when the store is dropped)
+    /// This is much faster, but all events since the last commit are lost if the process crashes.
+    pub fn open_batched(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let mut store = Self::open(path)?;
+        store.pending = Some(String::new());
+        Ok(store)
+    }
+
+    /// Append a single event to the log
+    fn append(&mut self, event: &str) -> Result<()> {
+        match &mut self.pending {
+            Some(pending) => {
+                pending.push_str(event);
+                pending.push('\n');
+            }
+            None => self.log.write_all(format!("{}\n", event).as_bytes())?,
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AppendLogTransactionStore {
+    fn drop(&mut self) {
+        if let Err(error) = self.commit() {
+            warn!("Cannot write pending log events: {}", error);
+        }
+    }
+}
+
+/// The transaction ID as a field of a log event
+/// IDs are written as they are, so they must not contain the separators of the log.
+fn logged_id(transaction: &TransactionId) -> Result<String> {
+    let id = transaction.to_string();
+    if id.contains([',', '\n', '\r']) {
+        return Err(anyhow!("Transaction ID cannot be logged (tx = {:?})", id));
+    }
+    Ok(id)
+}
+
+/// Apply one event of the log to the in-memory `index`
+fn replay(index: &mut HashMapTransactionStore, line: &str) -> Result<()> {
+    let fields: Vec<_> = line.split(',').collect();
+    let record = |client: &str, transaction: &str| -> Result<DisputedTransactionRecord> {
+        Ok(DisputedTransactionRecord {
+            client: client.parse()?,
+            transaction: transaction.parse()?,
+        })
+    };
+
+    match fields.as_slice() {
+        ["deposit", client, transaction, amount] => {
+            index.add_transaction(DisputableTransaction::Deposit(MonetaryTransactionRecord {
+                client: client.parse()?,
+                transaction: transaction.parse()?,
+                amount: amount.parse()?,
+            }))
+        }
+        ["withdrawal", client, transaction, amount] => index.add_transaction(
+            DisputableTransaction::Withdrawal(MonetaryTransactionRecord {
+                client: client.parse()?,
+                transaction: transaction.parse()?,
+                amount: amount.parse()?,
+            }),
+        ),
+        ["withdrawal", transaction] => {
+            index.add_withdrawal(transaction.parse()?);
+            Ok(())
+        }
+        ["dispute", client, transaction] => index
+            .dispute_transaction(&record(client, transaction)?)
+            .map(|_| ()),
+        ["resolve", client, transaction] => index
+            .undispute_transaction(&record(client, transaction)?, UndisputeOutcome::Resolve)
+            .map(|_| ()),
+        ["chargeback", client, transaction] => index
+            .undispute_transaction(&record(client, transaction)?, UndisputeOutcome::Chargeback)
+            .map(|_| ()),
+        _ => Err(anyhow!("Invalid log entry '{}'", line)),
+    }
+}
+
+impl TransactionStore for AppendLogTransactionStore {
+    fn add_transaction(&mut self, transaction: DisputableTransaction) -> Result<()> {
+        let kind = match &transaction {
+            DisputableTransaction::Deposit(_) => "deposit",
+            DisputableTransaction::Withdrawal(_) => "withdrawal",
+        };
+        let record = transaction.record();
+        let event = format!(
+            "{},{},{},{}",
+            kind,
+            record.client,
+            logged_id(&record.transaction)?,
+            record.amount
+        );
+
+        self.index.add_transaction(transaction)?;
+        self.append(&event)
+    }
+
+    /// Log failures can only be reported as a warning
+    fn add_withdrawal(&mut self, transaction: TransactionId) {
+        self.index.add_withdrawal(transaction);
+        let logged =
+            logged_id(&transaction).and_then(|id| self.append(&format!("withdrawal,{}", id)));
+        if let Err(error) = logged {
+            warn!("Cannot log withdrawal (tx = {}): {}", transaction, error);
+        }
+    }
+
+    fn is_withdrawal(&self, transaction: TransactionId) -> bool {
+        self.index.is_withdrawal(transaction)
+    }
+
+    fn transaction(&self, transaction: TransactionId) -> Option<DisputableTransaction> {
+        self.index.transaction(transaction)
+    }
+
+    fn dispute_transaction(
+        &mut self,
+        transaction: &DisputedTransactionRecord,
+    ) -> Result<DisputableTransaction> {
+        let id = logged_id(&transaction.transaction)?;
+        let disputed = self.index.dispute_transaction(transaction)?;
+        self.append(&format!("dispute,{},{}", transaction.client, id))?;
+        Ok(disputed)
+    }
+
+    fn undispute_transaction(
+        &mut self,
+        transaction: &DisputedTransactionRecord,
+        outcome: UndisputeOutcome,
+    ) -> Result<DisputableTransaction> {
+        let event = match outcome {
+            UndisputeOutcome::Resolve => "resolve",
+            UndisputeOutcome::Chargeback => "chargeback",
+        };
+
+        let id = logged_id(&transaction.transaction)?;
+        let undisputed = self.index.undispute_transaction(transaction, outcome)?;
+        self.append(&format!("{},{},{}", event, transaction.client, id))?;
+        Ok(undisputed)
+    }
+
+    fn dispute_report(&self) -> Vec<DisputeOutcome> {
+        self.index.dispute_report()
+    }
+
+    fn stored_transactions(&self) -> Vec<StoredTransaction> {
+        self.index.stored_transactions()
+    }
+
+    fn snapshot(&self, transaction: TransactionId) -> TransactionSnapshot {
+        self.index.snapshot(transaction)
+    }
+
+    /// Events cannot be removed from the log again
+    fn restore(&mut self, transaction: TransactionId, _: TransactionSnapshot) -> Result<()> {
+        Err(anyhow!(
+            "Logged changes cannot be undone (tx = {})",
+            transaction
+        ))
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if let Some(pending) = &mut self.pending {
+            self.log.write_all(pending.as_bytes())?;
+            pending.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+    use crate::test_support::tx;
+    use crate::types::*;
+
+    fn deposit(transaction: TransactionId) -> DisputableTransaction {
+        DisputableTransaction::Deposit(MonetaryTransactionRecord {
+            client: 0,
+            transaction,
+            amount: amount!(1.5),
+        })
+    }
+
+    fn record(transaction: TransactionId) -> DisputedTransactionRecord {
+        DisputedTransactionRecord {
+            client: 0,
+            transaction,
+        }
+    }
+
+    #[test]
+    fn restart_from_log() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-coding-test-append-log-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let mut store = AppendLogTransactionStore::open(&path).unwrap();
+            store.add_transaction(deposit(tx(1))).unwrap();
+            store.add_transaction(deposit(tx(2))).unwrap();
+            store.add_withdrawal(tx(3));
+            store.dispute_transaction(&record(tx(1))).unwrap();
+            store.dispute_transaction(&record(tx(4))).unwrap_err(); // not logged
+        }
+
+        let mut store = AppendLogTransactionStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.transaction(tx(2)), Some(deposit(tx(2))));
+        store.add_transaction(deposit(tx(1))).unwrap_err();
+
+        store.dispute_transaction(&record(tx(2))).unwrap();
+        store
+            .undispute_transaction(&record(tx(1)), UndisputeOutcome::Chargeback)
+            .unwrap();
+        let error = store
+            .undispute_transaction(&record(tx(3)), UndisputeOutcome::Resolve)
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&crate::error::TransactionError::NotDisputable { transaction: tx(3) })
+        );
+
+        let store = AppendLogTransactionStore::open(&path).unwrap();
+        let statuses: Vec<_> = store
+            .dispute_report()
+            .into_iter()
+            .map(|outcome| outcome.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![DisputeStatus::ChargedBack, DisputeStatus::Disputed]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_log() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-coding-test-invalid-log-{}.log",
+            std::process::id()
+        ));
+        std::fs::write(&path, "deposit,0,1,1.0\ndance,0,1\n").unwrap();
+
+        let error = AppendLogTransactionStore::open(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(format!("{:#}", error).contains("line 2"));
+    }
+
+    #[test]
+    fn batched_commits() {
+        let path = |name| {
+            std::env::temp_dir().join(format!(
+                "rust-coding-test-{}-{}.log",
+                name,
+                std::process::id()
+            ))
+        };
+        let (immediate_path, batched_path) = (path("immediate"), path("batched"));
+        let _ = std::fs::remove_file(&immediate_path);
+        let _ = std::fs::remove_file(&batched_path);
+
+        let mut immediate = AppendLogTransactionStore::open(&immediate_path).unwrap();
+        let mut batched = AppendLogTransactionStore::open_batched(&batched_path).unwrap();
+        for store in [&mut immediate, &mut batched] {
+            store.add_transaction(deposit(tx(1))).unwrap();
+            store.add_transaction(deposit(tx(2))).unwrap();
+            store.commit().unwrap();
+            store.add_withdrawal(tx(3));
+            store.dispute_transaction(&record(tx(1))).unwrap();
+        }
+
+        // only the first commit has been written so far
+        let read = |path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(&batched_path).lines().count(), 2);
+        batched.commit().unwrap();
+        assert_eq!(read(&batched_path), read(&immediate_path));
+
+        batched
+            .undispute_transaction(&record(tx(1)), UndisputeOutcome::Resolve)
+            .unwrap();
+        drop(batched); // commits the rest
+        let reopened = AppendLogTransactionStore::open(&batched_path).unwrap();
+        assert_eq!(reopened.dispute_report()[0].status, DisputeStatus::Resolved);
+
+        drop(immediate);
+        std::fs::remove_file(&immediate_path).unwrap();
+        std::fs::remove_file(&batched_path).unwrap();
+    }
+
+    #[test]
+    fn handler_commits_every_n_transactions() {
+        use crate::transaction_handler::{HandlerConfig, TransactionHandler};
+
+        let path = std::env::temp_dir().join(format!(
+            "rust-coding-test-handler-log-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let store = AppendLogTransactionStore::open_batched(&path).unwrap();
+        let mut handler = TransactionHandler::with_store(
+            HandlerConfig {
+                commit_interval: Some(2),
+                ..HandlerConfig::default()
+            },
+            store,
+        );
+
+        let mut transactions = vec![
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(1.5),
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+                amount: amount!(1.5),
+            }),
+            Transaction::Dispute(record(tx(1))),
+            Transaction::Resolve(record(tx(1))),
+            Transaction::Dispute(record(tx(2))),
+        ]
+        .into_iter()
+        .map(Ok);
+
+        let logged = || std::fs::read_to_string(&path).unwrap().lines().count();
+        handler.handle_n(&mut transactions, 1);
+        assert_eq!(logged(), 0);
+        handler.handle_n(&mut transactions, 1);
+        assert_eq!(logged(), 2);
+        handler.handle_n(&mut transactions, 1);
+        assert_eq!(logged(), 2);
+        handler.handle_n(&mut transactions, 1);
+        assert_eq!(logged(), 4);
+
+        // the end of the input is always committed
+        handler.handle_transactions(transactions);
+        assert_eq!(logged(), 5);
+
+        drop(handler);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn logged_changes_cannot_be_undone() {
+        use crate::transaction_handler::{HandlerConfig, TransactionHandler};
+
+        let path = std::env::temp_dir().join(format!(
+            "rust-coding-test-undo-log-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let store = AppendLogTransactionStore::open(&path).unwrap();
+        let mut handler = TransactionHandler::with_store(
+            HandlerConfig {
+                undo_depth: 1,
+                ..HandlerConfig::default()
+            },
+            store,
+        );
+        handler
+            .apply(Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(1.5),
+            }))
+            .unwrap();
+
+        // the deposit is neither undone in the log nor in the accounts
+        handler.undo_last().unwrap_err();
+        assert_eq!(handler.account_count(), 1);
+
+        drop(handler);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "string-tx-id")]
+    #[test]
+    fn ids_with_separators_are_not_logged() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-coding-test-separator-log-{}.log",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = AppendLogTransactionStore::open(&path).unwrap();
+        let deposit = |id: &str| {
+            DisputableTransaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: id.parse().unwrap(),
+                amount: amount!(1.5),
+            })
+        };
+        store.add_transaction(deposit("a,b")).unwrap_err();
+        store.add_transaction(deposit("a\nb")).unwrap_err();
+        store.add_transaction(deposit("a-b")).unwrap();
+        assert_eq!(store.len(), 1);
+
+        drop(store);
+        let store = AppendLogTransactionStore::open(&path).unwrap();
+        assert_eq!(store.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
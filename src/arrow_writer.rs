@@ -0,0 +1,159 @@
+use anyhow::Result;
+use arrow_array::{ArrayRef, BooleanArray, RecordBatch, UInt16Array};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+
+use crate::types::{Account, Amount};
+
+type AmountGetter = fn(&Account) -> Amount;
+
+/// Build a typed column of amounts, exact decimals use the largest scale of all `amounts`
+#[cfg(not(feature = "float-amount"))]
+fn amount_column(amounts: &[Amount]) -> Result<(DataType, ArrayRef)> {
+    use arrow_array::Decimal128Array;
+    use arrow_schema::DECIMAL128_MAX_PRECISION;
+
+    let scale = amounts
+        .iter()
+        .map(|amount| amount.scale())
+        .max()
+        .unwrap_or(0);
+    let values = amounts.iter().map(|amount| {
+        let mut amount = *amount;
+        amount.rescale(scale);
+        amount.mantissa()
+    });
+    let data_type = DataType::Decimal128(DECIMAL128_MAX_PRECISION, scale as i8);
+    let array = Decimal128Array::from_iter_values(values).with_data_type(data_type.clone());
+    Ok((data_type, Arc::new(array)))
+}
+
+/// Build a typed column of amounts
+#[cfg(feature = "float-amount")]
+fn amount_column(amounts: &[Amount]) -> Result<(DataType, ArrayRef)> {
+    use arrow_array::Float64Array;
+
+    let array = Float64Array::from(amounts.to_vec());
+    Ok((DataType::Float64, Arc::new(array)))
+}
+
+/// Write all accounts to the provided destination as an Arrow IPC file
+///
+/// The columns are the same as in the CSV output (`client`, `available`, `held`, `total`, and
+/// `locked`), but typed, so that the file can be loaded into a DataFrame without any parsing. All
+/// accounts are written as a single record batch.
+pub fn write_accounts_arrow(
+    destination: &mut dyn std::io::Write,
+    accounts: impl Iterator<Item = Account>,
+) -> Result<()> {
+    let accounts: Vec<_> = accounts.collect();
+
+    let mut fields = vec![Field::new("client", DataType::UInt16, false)];
+    let mut columns: Vec<ArrayRef> = vec![Arc::new(UInt16Array::from_iter_values(
+        accounts.iter().map(|account| account.client),
+    ))];
+
+    let amounts: [(&str, AmountGetter); 3] = [
+        ("available", |account| account.available),
+        ("held", |account| account.held),
+        ("total", Account::total),
+    ];
+    for (name, amount) in amounts.iter() {
+        let values: Vec<_> = accounts.iter().map(amount).collect();
+        let (data_type, column) = amount_column(&values)?;
+        fields.push(Field::new(*name, data_type, false));
+        columns.push(column);
+    }
+
+    fields.push(Field::new("locked", DataType::Boolean, false));
+    columns.push(Arc::new(BooleanArray::from(
+        accounts
+            .iter()
+            .map(|account| account.locked)
+            .collect::<Vec<_>>(),
+    )));
+
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+    let mut writer = FileWriter::try_new(destination, &schema)?;
+    writer.write(&batch)?;
+    writer.finish()?;
+    Ok(())
+}
+
+// The round trip decodes the `Decimal128` columns, which only exist for exact amounts
+#[cfg(all(test, not(feature = "float-amount")))]
+mod tests {
+    use super::*;
+    use arrow_array::{cast::AsArray, types::Decimal128Type};
+    use arrow_ipc::reader::FileReader;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn arrow_round_trip() {
+        let accounts = vec![
+            Account {
+                client: 1,
+                available: dec!(1.5),
+                held: dec!(0.25),
+                locked: false,
+            },
+            Account {
+                client: 2,
+                available: dec!(0),
+                held: dec!(0),
+                locked: true,
+            },
+        ];
+
+        let mut buffer = vec![];
+        write_accounts_arrow(&mut buffer, accounts.into_iter()).unwrap();
+
+        let batches: Vec<_> = FileReader::try_new(std::io::Cursor::new(buffer), None)
+            .unwrap()
+            .map(|batch| batch.unwrap())
+            .collect();
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+
+        let names: Vec<_> = batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().clone())
+            .collect();
+        assert_eq!(names, ["client", "available", "held", "total", "locked"]);
+
+        let clients: Vec<_> = batch
+            .column(0)
+            .as_primitive::<arrow_array::types::UInt16Type>()
+            .values()
+            .to_vec();
+        assert_eq!(clients, [1, 2]);
+
+        let amounts = |index: usize| -> Vec<Amount> {
+            let column = batch.column(index).as_primitive::<Decimal128Type>();
+            let scale = match batch.schema().field(index).data_type() {
+                DataType::Decimal128(_, scale) => *scale as u32,
+                data_type => panic!("Unexpected type {}", data_type),
+            };
+            column
+                .values()
+                .iter()
+                .map(|value| Amount::from_i128_with_scale(*value, scale))
+                .collect()
+        };
+        assert_eq!(amounts(1), [dec!(1.5), dec!(0)]);
+        assert_eq!(amounts(2), [dec!(0.25), dec!(0)]);
+        assert_eq!(amounts(3), [dec!(1.75), dec!(0)]);
+
+        let locked: Vec<_> = batch
+            .column(4)
+            .as_boolean()
+            .iter()
+            .map(Option::unwrap)
+            .collect();
+        assert_eq!(locked, [false, true]);
+    }
+}
@@ -1,9 +1,12 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 
+use crate::error::TransactionError;
 use crate::types::{
-    Amount, ClientId, DisputedTransactionRecord, MonetaryTransactionRecord, Transaction,
-    TransactionId,
+    Amount, ClientId, DisputedTransactionRecord, MonetaryTransactionRecord, SubMinorUnitPolicy,
+    Transaction, TransactionId, ValidationConfig,
 };
 
 /// The different transaction type identifiers as in the input CSV
@@ -23,51 +26,84 @@ struct RawTransaction {
     #[serde(rename = "type")]
     transaction_type: RawTransactionType,
 
+    /// Parsed wider than `ClientId`, so that an out-of-range ID gets a specific error
     #[serde(rename = "client")]
-    client: ClientId,
+    client: u64,
 
     #[serde(rename = "tx")]
     transaction: TransactionId,
 
     #[serde(rename = "amount")]
     amount: Option<Amount>,
+
+    /// Optional per-client sequence number for inputs that are not ordered globally
+    #[serde(rename = "seq", default)]
+    sequence: Option<u64>,
+}
+
+/// Check the amount of a deposit or withdrawal, it must be present and positive
+///
+/// The direction of the money flow is given by the transaction type, never by the sign. Only a
+/// missing amount with `ParserConfig::missing_amount_is_zero` results in a zero amount. The
+/// sub-minor-unit policy is applied first, so an amount rounded (or truncated) to zero is rejected.
+fn monetary_amount(
+    amount: Option<Amount>,
+    transaction: TransactionId,
+    transaction_type: &str,
+    config: &ParserConfig,
+) -> Result<Amount> {
+    let amount = match amount {
+        Some(amount) => amount,
+        None if config.missing_amount_is_zero => return Ok(Amount::default()),
+        None => {
+            return Err(anyhow!(
+                "No 'amount' for {} (tx = {})",
+                transaction_type,
+                transaction
+            ))
+        }
+    };
+    if amount < Amount::default() {
+        return Err(TransactionError::NegativeAmount { transaction }.into());
+    }
+    let amount = config.sub_minor_units.apply(amount, transaction)?;
+    if amount == Amount::default() {
+        return Err(TransactionError::ZeroAmount { transaction }.into());
+    }
+    Ok(amount)
 }
 
 /// Turn a `RawTransaction` into a `Transaction` that can be handled in a nicer way (no optional!)
 ///
 /// Should `Dispute`, `Resolve`, or `Chargeback` records include an `amount`, the `amount` will be
 /// silently discarded but the record will be kept.
-fn raw_to_transaction(raw: RawTransaction) -> Result<Transaction> {
+///
+/// With `ParserConfig::missing_amount_is_zero`, deposits and withdrawals without an `amount` are
+/// zero-value transactions instead of errors.
+fn raw_to_transaction(raw: RawTransaction, config: &ParserConfig) -> Result<Transaction> {
     let RawTransaction {
         transaction_type,
         client,
         transaction,
         amount,
+        sequence: _,
     } = raw;
+    let client = ClientId::try_from(client).map_err(|_| TransactionError::ClientIdOutOfRange {
+        client,
+        transaction,
+    })?;
 
     match transaction_type {
-        RawTransactionType::Deposit => {
-            if let Some(amount) = amount {
-                Ok(Transaction::Deposit(MonetaryTransactionRecord {
-                    client,
-                    transaction,
-                    amount,
-                }))
-            } else {
-                Err(anyhow!("No 'amount' for deposit (tx = {})", transaction))
-            }
-        }
-        RawTransactionType::Withdrawal => {
-            if let Some(amount) = amount {
-                Ok(Transaction::Withdrawal(MonetaryTransactionRecord {
-                    client,
-                    transaction,
-                    amount,
-                }))
-            } else {
-                Err(anyhow!("No 'amount' for withdrawal (tx = {})", transaction))
-            }
-        }
+        RawTransactionType::Deposit => Ok(Transaction::Deposit(MonetaryTransactionRecord {
+            client,
+            transaction,
+            amount: monetary_amount(amount, transaction, "deposit", config)?,
+        })),
+        RawTransactionType::Withdrawal => Ok(Transaction::Withdrawal(MonetaryTransactionRecord {
+            client,
+            transaction,
+            amount: monetary_amount(amount, transaction, "withdrawal", config)?,
+        })),
         RawTransactionType::Dispute => Ok(Transaction::Dispute(DisputedTransactionRecord {
             client,
             transaction,
@@ -83,21 +119,212 @@ fn raw_to_transaction(raw: RawTransaction) -> Result<Transaction> {
     }
 }
 
+/// Check the amounts of deposits and withdrawals
+fn validate_transaction(
+    transaction: Transaction,
+    config: &ValidationConfig,
+) -> Result<Transaction> {
+    match &transaction {
+        Transaction::Deposit(record) | Transaction::Withdrawal(record) => {
+            record.validate(config)?
+        }
+        Transaction::Dispute(_) | Transaction::Resolve(_) | Transaction::Chargeback(_) => {}
+    }
+    Ok(transaction)
+}
+
+/// Copy a CSV record with the field at `index` converted to lowercase
+fn lowercase_field(record: &csv::StringRecord, index: usize) -> csv::StringRecord {
+    let mut lowercased: csv::StringRecord = record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            if i == index {
+                field.to_lowercase()
+            } else {
+                field.to_string()
+            }
+        })
+        .collect();
+    lowercased.set_position(record.position().cloned());
+    lowercased
+}
+
+/// Parse a single CSV record into a `Transaction` and its sequence number (if any)
+fn record_to_transaction(
+    record: &csv::StringRecord,
+    headers: Option<&csv::StringRecord>,
+    config: &ParserConfig,
+) -> (Option<u64>, Result<Transaction>) {
+    match record.deserialize::<RawTransaction>(headers) {
+        Ok(raw) => (
+            raw.sequence,
+            raw_to_transaction(raw, config)
+                .and_then(|transaction| validate_transaction(transaction, &config.validation)),
+        ),
+        Err(error) => (None, Err(error.into())),
+    }
+}
+
+/// Reorder the transactions of each client by their sequence numbers
+///
+/// The positions in the stream occupied by a client stay the same, only the client's own
+/// transactions are swapped among them. Rows that could not be parsed keep their position.
+fn order_by_sequence(rows: Vec<(Option<u64>, Result<Transaction>)>) -> Vec<Result<Transaction>> {
+    let mut positions: HashMap<ClientId, Vec<usize>> = HashMap::new();
+    let mut sequences = Vec::with_capacity(rows.len());
+    let mut rows: Vec<_> = rows
+        .into_iter()
+        .enumerate()
+        .map(|(index, (sequence, transaction))| {
+            if let Ok(transaction) = &transaction {
+                positions
+                    .entry(transaction.client())
+                    .or_default()
+                    .push(index);
+            }
+            sequences.push(sequence);
+            Some(transaction)
+        })
+        .collect();
+
+    for slots in positions.values() {
+        let mut ordered = slots.clone();
+        ordered.sort_by_key(|index| sequences[*index]);
+
+        let moved: Vec<_> = ordered.iter().map(|index| rows[*index].take()).collect();
+        for (slot, transaction) in slots.iter().zip(moved) {
+            rows[*slot] = transaction;
+        }
+    }
+    rows.into_iter().flatten().collect()
+}
+
+/// Options to adjust how the input is interpreted, the default is strict
+#[derive(Debug, Clone, Default)]
+pub struct ParserConfig {
+    /// Skip rows that repeat the header (e.g. from naively concatenated files) instead of failing
+    pub skip_repeated_headers: bool,
+
+    /// Rules that the amounts of deposits and withdrawals must follow
+    pub validation: ValidationConfig,
+
+    /// Accept transaction types in any case (e.g. `Deposit` or `DEPOSIT`)
+    pub case_insensitive_types: bool,
+
+    /// Reject rows with more columns than the header instead of ignoring the extra columns
+    pub reject_extra_columns: bool,
+
+    /// Treat deposits and withdrawals without an `amount` as zero-value instead of failing them
+    /// This is the only way to get a zero amount, an explicit `0` is still rejected with
+    /// `TransactionError::ZeroAmount`.
+    pub missing_amount_is_zero: bool,
+
+    /// What to do with deposits and withdrawals with a fraction of a minor unit (e.g. `0.00001`)
+    pub sub_minor_units: SubMinorUnitPolicy,
+}
+
 /// For each line of the input (skipping the header), read a line by line `Transaction` record.
-pub fn iter_transactions(reader: impl std::io::Read) -> impl Iterator<Item = Result<Transaction>> {
-    csv::ReaderBuilder::new()
+///
+/// Errors carry the (1-based) input line of the offending record as context, use the alternate
+/// format (`{:#}`) to display it together with the actual error message.
+pub fn iter_transactions<'a>(
+    reader: impl std::io::Read + 'a,
+) -> impl Iterator<Item = Result<Transaction>> + 'a {
+    iter_transactions_with_config(reader, &ParserConfig::default())
+}
+
+/// Parse several readers as one logical stream of transactions, each reader has its own header
+pub fn iter_transactions_chained(
+    readers: Vec<Box<dyn std::io::Read>>,
+) -> impl Iterator<Item = Result<Transaction>> {
+    readers.into_iter().flat_map(iter_transactions)
+}
+
+/// Like `iter_transactions`, but using the given options
+///
+/// If the input has a `seq` column, the whole input is buffered in memory and each client's
+/// transactions are applied in the order of their sequence numbers instead of the stream order.
+pub fn iter_transactions_with_config<'a>(
+    reader: impl std::io::Read + 'a,
+    config: &ParserConfig,
+) -> impl Iterator<Item = Result<Transaction>> + 'a {
+    let config = config.clone();
+    let mut reader = csv::ReaderBuilder::new()
         .trim(csv::Trim::All)
         .flexible(true)
-        .from_reader(reader)
-        .into_deserialize()
-        .map(|raw| raw.map_err(Into::into).and_then(raw_to_transaction))
+        .from_reader(reader);
+    let headers = reader.headers().ok().cloned();
+    let sequenced = headers
+        .as_ref()
+        .is_some_and(|headers| headers.iter().any(|header| header == "seq"));
+    let type_column = headers
+        .as_ref()
+        .and_then(|headers| headers.iter().position(|header| header == "type"))
+        .unwrap_or(0);
+
+    let rows = reader.into_records().filter_map(move |record| {
+        let record = match record {
+            Ok(record) => record,
+            Err(error) => return Some((None, Err(error.into()))),
+        };
+
+        // e.g. a trailing line with only whitespace, which the `csv` crate does not drop itself
+        if record.iter().all(str::is_empty) {
+            debug!("Skipping blank row");
+            return None;
+        }
+
+        if config.skip_repeated_headers && Some(&record) == headers.as_ref() {
+            debug!("Skipping repeated header row");
+            return None;
+        }
+
+        let record = if config.case_insensitive_types {
+            lowercase_field(&record, type_column)
+        } else {
+            record
+        };
+
+        let (sequence, mut transaction) = record_to_transaction(&record, headers.as_ref(), &config);
+        if sequenced && sequence.is_none() && transaction.is_ok() {
+            transaction = Err(anyhow!("No 'seq' in sequenced input"));
+        }
+
+        let columns = headers
+            .as_ref()
+            .map_or(record.len(), |headers| headers.len());
+        if config.reject_extra_columns && record.len() > columns && transaction.is_ok() {
+            transaction = Err(anyhow!(
+                "Unexpected extra columns ({} instead of {})",
+                record.len(),
+                columns
+            ));
+        }
+
+        Some(match record.position() {
+            Some(position) => (
+                sequence,
+                transaction.with_context(|| format!("line {}", position.line())),
+            ),
+            None => (sequence, transaction),
+        })
+    });
+
+    let transactions: Box<dyn Iterator<Item = Result<Transaction>> + 'a> = if sequenced {
+        Box::new(order_by_sequence(rows.collect()).into_iter())
+    } else {
+        Box::new(rows.map(|(_, transaction)| transaction))
+    };
+    transactions
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::tx;
 
-    use rust_decimal_macros::dec;
+    use crate::test_support::amount;
 
     #[test]
     fn empty_file() {
@@ -117,8 +344,8 @@ deposit, 0, 1, 2
             entries,
             vec![Transaction::Deposit(MonetaryTransactionRecord {
                 client: 0,
-                transaction: 1,
-                amount: dec!(2)
+                transaction: tx(1),
+                amount: amount!(2)
             })]
         );
     }
@@ -134,7 +361,7 @@ dispute, 0, 1
             entries,
             vec![Transaction::Dispute(DisputedTransactionRecord {
                 client: 0,
-                transaction: 1,
+                transaction: tx(1),
             })]
         );
     }
@@ -144,7 +371,7 @@ dispute, 0, 1
         let buffer = br#"
 type, client, tx, amount
 deposit, 0, 1, 2.5
-withdrawal, 3, 4, -5.1
+withdrawal, 3, 4, 5.1
 dispute, 6, 7,
 resolve, 8, 9,
 chargeback, 10, 11,
@@ -155,25 +382,25 @@ chargeback, 10, 11,
             vec![
                 Transaction::Deposit(MonetaryTransactionRecord {
                     client: 0,
-                    transaction: 1,
-                    amount: dec!(2.5)
+                    transaction: tx(1),
+                    amount: amount!(2.5)
                 }),
                 Transaction::Withdrawal(MonetaryTransactionRecord {
                     client: 3,
-                    transaction: 4,
-                    amount: dec!(-5.1)
+                    transaction: tx(4),
+                    amount: amount!(5.1)
                 }),
                 Transaction::Dispute(DisputedTransactionRecord {
                     client: 6,
-                    transaction: 7,
+                    transaction: tx(7),
                 }),
                 Transaction::Resolve(DisputedTransactionRecord {
                     client: 8,
-                    transaction: 9,
+                    transaction: tx(9),
                 }),
                 Transaction::Chargeback(DisputedTransactionRecord {
                     client: 10,
-                    transaction: 11,
+                    transaction: tx(11),
                 })
             ]
         );
@@ -196,4 +423,399 @@ chargeback, 10, 11,
         assert!(entries[3].is_err()); // unsupported type
         assert!(entries[4].is_ok()); // all good
     }
+
+    #[test]
+    fn line_number_in_error() {
+        let buffer = br#"
+type, client, tx, amount
+deposit, 0, 1, 2
+withdrawal, 3, 4
+chargeback, 10, 11,
+"#;
+        let entries: Vec<_> = iter_transactions(&buffer[..]).collect();
+        assert!(entries[0].is_ok());
+        assert!(entries[2].is_ok());
+
+        let error = entries[1].as_ref().unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "line 4: No 'amount' for withdrawal (tx = 4)"
+        );
+    }
+
+    #[test]
+    fn repeated_header() {
+        let buffer = br#"
+type, client, tx, amount
+deposit, 0, 1, 2
+type, client, tx, amount
+deposit, 0, 2, 3
+"#;
+        let entries: Vec<_> = iter_transactions(&buffer[..]).collect();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[1].is_err());
+
+        let config = ParserConfig {
+            skip_repeated_headers: true,
+            ..ParserConfig::default()
+        };
+        let entries: Vec<_> = iter_transactions_with_config(&buffer[..], &config)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                Transaction::Deposit(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                    amount: amount!(2)
+                }),
+                Transaction::Deposit(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(2),
+                    amount: amount!(3)
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn ordered_by_sequence() {
+        let buffer = br#"
+type, client, tx, amount, seq
+dispute, 0, 1, , 2
+deposit, 1, 5, 4.0, 1
+deposit, 0, 1, 2.0, 1
+withdrawal, 0, 2, 1.0, 3
+"#;
+        let entries: Vec<_> = iter_transactions(&buffer[..]).map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![
+                Transaction::Deposit(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                    amount: amount!(2.0)
+                }),
+                Transaction::Deposit(MonetaryTransactionRecord {
+                    client: 1,
+                    transaction: tx(5),
+                    amount: amount!(4.0)
+                }),
+                Transaction::Dispute(DisputedTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                }),
+                Transaction::Withdrawal(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(2),
+                    amount: amount!(1.0)
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_sequence() {
+        let buffer = br#"
+type, client, tx, amount, seq
+deposit, 0, 1, 2.0, 1
+deposit, 0, 2, 2.0
+"#;
+        let entries: Vec<_> = iter_transactions(&buffer[..]).collect();
+        assert!(entries[0].is_ok());
+        assert!(entries[1].is_err());
+    }
+
+    #[test]
+    fn chained_readers() {
+        let first = br#"
+type, client, tx, amount
+deposit, 0, 1, 2
+"#;
+        let second = br#"type,client,tx
+dispute,0,1
+"#;
+        let readers: Vec<Box<dyn std::io::Read>> =
+            vec![Box::new(&first[..]), Box::new(&second[..])];
+        let entries: Vec<_> = iter_transactions_chained(readers)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                Transaction::Deposit(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                    amount: amount!(2)
+                }),
+                Transaction::Dispute(DisputedTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn validated_amounts() {
+        let buffer = br#"
+type, client, tx, amount
+deposit, 0, 1, 2.12345
+withdrawal, 0, 2, -1
+dispute, 0, 1,
+"#;
+        let config = ParserConfig {
+            validation: ValidationConfig {
+                reject_negative: true,
+                max_scale: Some(4),
+                ..ValidationConfig::default()
+            },
+            ..ParserConfig::default()
+        };
+        let entries: Vec<_> = iter_transactions_with_config(&buffer[..], &config).collect();
+        assert_eq!(
+            entries[0].as_ref().unwrap_err().downcast_ref(),
+            Some(&TransactionError::ExcessiveScale {
+                transaction: tx(1),
+                max_scale: 4
+            })
+        );
+        assert_eq!(
+            entries[1].as_ref().unwrap_err().downcast_ref(),
+            Some(&TransactionError::NegativeAmount { transaction: tx(2) })
+        );
+        assert!(entries[2].is_ok());
+    }
+
+    #[test]
+    fn case_insensitive_types() {
+        let buffer = br#"
+type, client, tx, amount
+Deposit, 0, 1, 2
+WITHDRAWAL, 0, 2, 1
+"#;
+        assert!(iter_transactions(&buffer[..]).all(|entry| entry.is_err()));
+
+        let config = ParserConfig {
+            case_insensitive_types: true,
+            ..ParserConfig::default()
+        };
+        let entries: Vec<_> = iter_transactions_with_config(&buffer[..], &config)
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                Transaction::Deposit(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                    amount: amount!(2)
+                }),
+                Transaction::Withdrawal(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(2),
+                    amount: amount!(1)
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn trailing_blank_lines() {
+        let buffer = b"type, client, tx, amount\ndeposit, 0, 1, 2   \n   \n\n \t \n";
+        let entries: Vec<_> = iter_transactions(&buffer[..]).map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(2)
+            })]
+        );
+    }
+
+    #[test]
+    fn non_positive_amounts() {
+        let buffer = br#"
+type, client, tx, amount
+deposit, 0, 1, -2.5
+withdrawal, 0, 2, -1
+deposit, 0, 3, 0
+withdrawal, 0, 4, 0.0
+dispute, 0, 5, -1
+"#;
+        let entries: Vec<_> = iter_transactions(&buffer[..]).collect();
+        assert_eq!(
+            entries[0].as_ref().unwrap_err().downcast_ref(),
+            Some(&TransactionError::NegativeAmount { transaction: tx(1) })
+        );
+        assert_eq!(
+            entries[1].as_ref().unwrap_err().downcast_ref(),
+            Some(&TransactionError::NegativeAmount { transaction: tx(2) })
+        );
+        assert_eq!(
+            entries[2].as_ref().unwrap_err().downcast_ref(),
+            Some(&TransactionError::ZeroAmount { transaction: tx(3) })
+        );
+        assert_eq!(
+            entries[3].as_ref().unwrap_err().downcast_ref(),
+            Some(&TransactionError::ZeroAmount { transaction: tx(4) })
+        );
+        assert!(entries[4].is_ok());
+
+        // the constructors agree with the parser
+        assert_eq!(
+            entries[2].as_ref().unwrap_err().downcast_ref(),
+            Transaction::deposit(0, tx(3), amount!(0)).err().as_ref()
+        );
+        assert_eq!(
+            entries[1].as_ref().unwrap_err().downcast_ref(),
+            Transaction::withdrawal(0, tx(2), amount!(-1))
+                .err()
+                .as_ref()
+        );
+    }
+
+    #[test]
+    fn extra_columns() {
+        let buffer = br#"
+type, client, tx, amount
+deposit, 1, 2, 3.0, extra
+dispute, 1, 2,
+"#;
+        let entries: Vec<_> = iter_transactions(&buffer[..]).map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries[0],
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(2),
+                amount: amount!(3.0)
+            })
+        );
+
+        let config = ParserConfig {
+            reject_extra_columns: true,
+            ..ParserConfig::default()
+        };
+        let entries: Vec<_> = iter_transactions_with_config(&buffer[..], &config).collect();
+        assert!(entries[0].is_err());
+        assert!(entries[1].is_ok());
+    }
+
+    #[test]
+    fn missing_amount_is_zero() {
+        let buffer = br#"
+type, client, tx, amount
+deposit, 0, 1,
+withdrawal, 0, 2,
+deposit, 0, 3, 0
+"#;
+        let errors: Vec<_> = iter_transactions(&buffer[..])
+            .map(|r| format!("{:#}", r.unwrap_err()))
+            .collect();
+        assert!(errors[0].contains("No 'amount' for deposit"));
+        assert!(errors[1].contains("No 'amount' for withdrawal"));
+
+        let config = ParserConfig {
+            missing_amount_is_zero: true,
+            ..ParserConfig::default()
+        };
+        let mut entries: Vec<_> = iter_transactions_with_config(&buffer[..], &config).collect();
+        // an explicit zero is still rejected
+        assert_eq!(
+            entries.pop().unwrap().unwrap_err().downcast_ref(),
+            Some(&TransactionError::ZeroAmount { transaction: tx(3) })
+        );
+        let entries: Vec<_> = entries.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![
+                Transaction::Deposit(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                    amount: amount!(0)
+                }),
+                Transaction::Withdrawal(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(2),
+                    amount: amount!(0)
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn sub_minor_units() {
+        let buffer = br#"
+type, client, tx, amount
+deposit, 0, 1, 0.00001
+deposit, 0, 2, 0.00005
+withdrawal, 0, 3, 1.5
+"#;
+        let amounts = |policy| -> Vec<_> {
+            let config = ParserConfig {
+                sub_minor_units: policy,
+                ..ParserConfig::default()
+            };
+            iter_transactions_with_config(&buffer[..], &config)
+                .map(|entry| match entry {
+                    Ok(Transaction::Deposit(record)) | Ok(Transaction::Withdrawal(record)) => {
+                        Ok(record.amount)
+                    }
+                    Ok(_) => unreachable!(),
+                    Err(error) => Err(error.downcast_ref::<TransactionError>().cloned().unwrap()),
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            amounts(SubMinorUnitPolicy::Keep),
+            vec![Ok(amount!(0.00001)), Ok(amount!(0.00005)), Ok(amount!(1.5))]
+        );
+
+        // amounts that become zero are rejected like any other zero amount
+        let zero = |transaction| TransactionError::ZeroAmount { transaction };
+        assert_eq!(
+            amounts(SubMinorUnitPolicy::Round),
+            vec![Err(zero(tx(1))), Ok(amount!(0.0001)), Ok(amount!(1.5))]
+        );
+        assert_eq!(
+            amounts(SubMinorUnitPolicy::Truncate),
+            vec![Err(zero(tx(1))), Err(zero(tx(2))), Ok(amount!(1.5))]
+        );
+        let excessive = |transaction| TransactionError::ExcessiveScale {
+            transaction,
+            max_scale: 4,
+        };
+        assert_eq!(
+            amounts(SubMinorUnitPolicy::Reject),
+            vec![
+                Err(excessive(tx(1))),
+                Err(excessive(tx(2))),
+                Ok(amount!(1.5))
+            ]
+        );
+    }
+
+    #[test]
+    fn client_id_out_of_range() {
+        let buffer = br#"
+type, client, tx, amount
+deposit, 65535, 1, 1.0
+deposit, 70000, 2, 1.0
+"#;
+        let entries: Vec<_> = iter_transactions(&buffer[..]).collect();
+        assert_eq!(entries[0].as_ref().unwrap().client(), 65535);
+
+        let error = entries[1].as_ref().unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&TransactionError::ClientIdOutOfRange {
+                client: 70000,
+                transaction: tx(2)
+            })
+        );
+        assert!(format!("{:#}", error).contains("a wider `ClientId` type is needed"));
+    }
 }
@@ -1,7 +1,21 @@
-use anyhow::Result;
-use serde::{ser::SerializeStruct, Serialize, Serializer};
+use anyhow::{anyhow, Result};
+use serde::{
+    ser::{Error, SerializeStruct},
+    Deserialize, Serialize, Serializer,
+};
+use std::collections::HashMap;
 
-use crate::types::Account;
+use crate::transaction_handler::{LiquiditySample, Reconciliation};
+use crate::types::{Account, Amount, ClientId, DisputeStatus, StoredTransaction};
+
+/// Drop the sign of a (negative) zero so that it is never written as `-0`, the scale is kept
+fn canonical_amount(amount: Amount) -> Amount {
+    if amount == Amount::default() {
+        amount.abs()
+    } else {
+        amount
+    }
+}
 
 /// Use a custom serializer to compute the "total" balance on the fly
 impl Serialize for Account {
@@ -11,34 +25,456 @@ impl Serialize for Account {
     {
         let mut state = serializer.serialize_struct("Account", 5)?;
         state.serialize_field("client", &self.client)?;
-        state.serialize_field("available", &self.available)?;
-        state.serialize_field("held", &self.held)?;
-        state.serialize_field("total", &self.total())?;
+        state.serialize_field("available", &canonical_amount(self.available))?;
+        state.serialize_field("held", &canonical_amount(self.held))?;
+        state.serialize_field("total", &canonical_amount(self.total()))?;
         state.serialize_field("locked", &self.locked)?;
         state.end()
     }
 }
 
+/// Options to adjust the produced output, the default writes plain CSV
+#[derive(Debug, Clone, Default)]
+pub struct WriterConfig {
+    /// Text to emit as `#`-prefixed comment line(s) before the CSV header
+    /// Every line break (`\n`, `\r\n`, or `\r`) starts a new comment line and empty lines are
+    /// written as a bare `#`, so `Some("")` results in a single `#` line.
+    pub comment: Option<String>,
+
+    /// Add an `overdrawn` column which is `true` for accounts with negative available funds
+    pub overdrawn_column: bool,
+
+    /// Write the header row even if there are no accounts
+    pub always_header: bool,
+
+    /// Write all amounts as integers in minor units (scaled by 10^4, e.g. `1.5` becomes `15000`)
+    /// Amounts with more than 4 decimal places cannot be written and fail the output.
+    pub minor_units: bool,
+
+    /// Log and skip accounts that cannot be written instead of failing the whole output
+    pub skip_failed_rows: bool,
+
+    /// Round the available and held funds to this many decimal places (the total is their sum)
+    pub round_to_scale: Option<u32>,
+
+    /// Log a warning for every account whose funds are changed by `round_to_scale`
+    pub warn_precision_loss: bool,
+
+    /// Write equal amounts identically regardless of their scale (e.g. `1.0` and `1.00` are both
+    /// written as `1.0000`), amounts with more than 4 decimal places keep all of them
+    pub canonical_scale: bool,
+
+    /// How the `locked` column is rendered
+    pub locked_format: BooleanFormat,
+
+    /// What to do with accounts whose total funds are negative (a sign of an accounting problem)
+    pub negative_total: NegativeTotalPolicy,
+
+    /// Add a `disputed` column with the number of currently disputed transactions of each client
+    /// (see `TransactionHandler::open_disputes`), clients missing in the map have none
+    pub disputed_counts: Option<HashMap<ClientId, usize>>,
+}
+
+/// Handling of accounts with negative total funds in the output
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum NegativeTotalPolicy {
+    /// Write them like any other account
+    #[default]
+    Emit,
+
+    /// Write them, but log a warning
+    Flag,
+
+    /// Fail the output (or skip the account with `WriterConfig::skip_failed_rows`)
+    Reject,
+}
+
+/// Rendering of a boolean column
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BooleanFormat {
+    /// `true` or `false`
+    #[default]
+    TrueFalse,
+
+    /// `1` or `0`
+    OneZero,
+
+    /// `Y` or `N`
+    YesNo,
+}
+
+impl BooleanFormat {
+    fn render(self, value: bool) -> &'static str {
+        match (self, value) {
+            (BooleanFormat::TrueFalse, true) => "true",
+            (BooleanFormat::TrueFalse, false) => "false",
+            (BooleanFormat::OneZero, true) => "1",
+            (BooleanFormat::OneZero, false) => "0",
+            (BooleanFormat::YesNo, true) => "Y",
+            (BooleanFormat::YesNo, false) => "N",
+        }
+    }
+}
+
+/// Number of decimal places of amounts with a canonical scale
+#[cfg(not(feature = "float-amount"))]
+const CANONICAL_SCALE: u32 = 4;
+
+/// Pad the amount to (at least) `CANONICAL_SCALE` decimal places after dropping trailing zeros
+#[cfg(not(feature = "float-amount"))]
+fn with_canonical_scale(amount: Amount) -> Amount {
+    let mut amount = amount.normalize();
+    if amount.scale() < CANONICAL_SCALE {
+        amount.rescale(CANONICAL_SCALE);
+    }
+    amount
+}
+
+/// Floats have no scale, they are always written in their shortest representation
+#[cfg(feature = "float-amount")]
+fn with_canonical_scale(amount: Amount) -> Amount {
+    amount
+}
+
+/// Counts of the special cases encountered while writing accounts
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteStatistics {
+    /// Accounts not written due to `WriterConfig::skip_failed_rows`
+    pub skipped: usize,
+
+    /// Accounts whose funds were changed by `WriterConfig::round_to_scale`
+    pub rounded: usize,
+
+    /// Accounts with negative total funds (whether they were written or not)
+    pub negative_totals: usize,
+}
+
+/// Round the amount to `scale` decimal places (half away from zero)
+#[cfg(not(feature = "float-amount"))]
+fn round_amount(amount: Amount, scale: u32) -> Amount {
+    amount.round_dp_with_strategy(scale, rust_decimal::RoundingStrategy::MidpointAwayFromZero)
+}
+
+/// Round the amount to `scale` decimal places (half away from zero)
+#[cfg(feature = "float-amount")]
+fn round_amount(amount: Amount, scale: u32) -> Amount {
+    let factor = 10_f64.powi(scale as i32);
+    (amount * factor).round() / factor
+}
+
+/// Number of decimal places covered by the minor units
+const MINOR_UNIT_SCALE: u32 = 4;
+
+/// Scale the amount to minor units, `None` if it has too many decimal places (or is too large)
+#[cfg(not(feature = "float-amount"))]
+fn to_minor_units(amount: Amount) -> Option<i64> {
+    use rust_decimal::prelude::ToPrimitive;
+
+    let scaled = amount * Amount::from(10_i64.pow(MINOR_UNIT_SCALE));
+    if scaled.normalize().scale() > 0 {
+        return None;
+    }
+    scaled.to_i64()
+}
+
+/// Scale the amount to minor units, `None` if it has too many decimal places (or is too large)
+#[cfg(feature = "float-amount")]
+fn to_minor_units(amount: Amount) -> Option<i64> {
+    let scaled = amount * 10_f64.powi(MINOR_UNIT_SCALE as i32);
+    let rounded = scaled.round();
+    if (scaled - rounded).abs() > 1e-6 || rounded.abs() > i64::MAX as f64 {
+        return None;
+    }
+    Some(rounded as i64)
+}
+
+/// An account together with the options that control how it is written
+struct AccountRow<'a> {
+    account: &'a Account,
+    config: &'a WriterConfig,
+}
+
+impl<'a> Serialize for AccountRow<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let account = self.account;
+        let fields = 5
+            + usize::from(self.config.overdrawn_column)
+            + usize::from(self.config.disputed_counts.is_some());
+
+        let mut amounts = [
+            ("available", canonical_amount(account.available)),
+            ("held", canonical_amount(account.held)),
+            ("total", canonical_amount(account.total())),
+        ];
+        if self.config.canonical_scale {
+            for (_, amount) in &mut amounts {
+                *amount = with_canonical_scale(*amount);
+            }
+        }
+
+        // Convert everything before the first field is written, so a failing row leaves no trace
+        let mut minor_units = [0; 3];
+        if self.config.minor_units {
+            for (converted, (_, amount)) in minor_units.iter_mut().zip(amounts) {
+                *converted = to_minor_units(amount).ok_or_else(|| {
+                    S::Error::custom(format!(
+                        "Cannot write {} as minor units (client = {})",
+                        amount, account.client
+                    ))
+                })?;
+            }
+        }
+
+        let mut state = serializer.serialize_struct("Account", fields)?;
+        state.serialize_field("client", &account.client)?;
+        for (&(name, amount), minor_units) in amounts.iter().zip(minor_units) {
+            if self.config.minor_units {
+                state.serialize_field(name, &minor_units)?;
+            } else {
+                state.serialize_field(name, &amount)?;
+            }
+        }
+        state.serialize_field("locked", self.config.locked_format.render(account.locked))?;
+        if self.config.overdrawn_column {
+            state.serialize_field("overdrawn", &(account.available < Amount::default()))?;
+        }
+        if let Some(counts) = &self.config.disputed_counts {
+            let disputed = counts.get(&account.client).copied().unwrap_or(0);
+            state.serialize_field("disputed", &disputed)?;
+        }
+        state.end()
+    }
+}
+
 /// Write all accounts to the provided destination (in CSV format)
 pub fn write_accounts(
     destination: &mut dyn std::io::Write,
     accounts: impl Iterator<Item = Account>,
 ) -> Result<()> {
+    write_accounts_with_config(destination, accounts, &WriterConfig::default())?;
+    Ok(())
+}
+
+/// Write all accounts to the provided destination (in CSV format) using the given options
+pub fn write_accounts_with_config(
+    destination: &mut dyn std::io::Write,
+    accounts: impl Iterator<Item = Account>,
+    config: &WriterConfig,
+) -> Result<WriteStatistics> {
+    write_accounts_with_mapper(destination, accounts, config, |account| account)
+}
+
+/// Like `write_accounts_with_config`, but transform each account (e.g. mask the client ID) before
+/// it is written
+pub fn write_accounts_with_mapper(
+    destination: &mut dyn std::io::Write,
+    accounts: impl Iterator<Item = Account>,
+    config: &WriterConfig,
+    mapper: impl FnMut(Account) -> Account,
+) -> Result<WriteStatistics> {
+    // The `csv` crate cannot write comments, so they go directly to the destination
+    if let Some(comment) = &config.comment {
+        // A line break that is not followed by `#` would end up in the CSV data
+        for line in comment.replace("\r\n", "\n").split(['\r', '\n']) {
+            if line.is_empty() {
+                writeln!(destination, "#")?;
+            } else {
+                writeln!(destination, "# {}", line)?;
+            }
+        }
+    }
+
     let mut writer = csv::WriterBuilder::new().from_writer(destination);
 
-    for account in accounts {
-        if let Err(err) = writer.serialize(account) {
-            return Err(err.into());
+    let mut accounts = accounts.map(mapper).peekable();
+    if config.always_header && accounts.peek().is_none() {
+        // The header is otherwise derived from the first serialized row
+        let mut header = vec!["client", "available", "held", "total", "locked"];
+        if config.overdrawn_column {
+            header.push("overdrawn");
+        }
+        if config.disputed_counts.is_some() {
+            header.push("disputed");
+        }
+        writer.write_record(&header)?;
+    }
+
+    let mut statistics = WriteStatistics::default();
+    for mut account in accounts {
+        if let Some(scale) = config.round_to_scale {
+            let available = round_amount(account.available, scale);
+            let held = round_amount(account.held, scale);
+            if available != account.available || held != account.held {
+                if config.warn_precision_loss {
+                    warn!(
+                        "Rounding to {} decimal places loses precision (client = {}, available = \
+                         {}, held = {})",
+                        scale, account.client, account.available, account.held
+                    );
+                }
+                statistics.rounded += 1;
+                account.available = available;
+                account.held = held;
+            }
+        }
+
+        // Every later use of the total relies on it being representable
+        let total = match account.checked_total() {
+            Some(total) => total,
+            None => {
+                let message = format!("Total funds overflow (client = {})", account.client);
+                if !config.skip_failed_rows {
+                    return Err(anyhow!(message));
+                }
+                warn!("Skipping account in output: {}", message);
+                statistics.skipped += 1;
+                continue;
+            }
+        };
+
+        if total < Amount::default() {
+            statistics.negative_totals += 1;
+            let message = format!(
+                "Negative total funds of {} (client = {})",
+                total, account.client
+            );
+            match config.negative_total {
+                NegativeTotalPolicy::Emit => {}
+                NegativeTotalPolicy::Flag => warn!("{}", message),
+                NegativeTotalPolicy::Reject if config.skip_failed_rows => {
+                    warn!("Skipping account in output: {}", message);
+                    statistics.skipped += 1;
+                    continue;
+                }
+                NegativeTotalPolicy::Reject => return Err(anyhow!(message)),
+            }
+        }
+
+        let row = AccountRow {
+            account: &account,
+            config,
+        };
+        if let Err(err) = writer.serialize(row) {
+            // I/O errors would affect all further rows as well
+            if !config.skip_failed_rows || matches!(err.kind(), csv::ErrorKind::Io(_)) {
+                return Err(err.into());
+            }
+            warn!("Skipping account in output: {}", err);
+            statistics.skipped += 1;
         }
     }
+    Ok(statistics)
+}
+
+/// One row of the account output as written with the default `WriterConfig`
+#[derive(Debug, Deserialize)]
+struct RawAccount {
+    client: ClientId,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+/// Parse accounts written by `write_accounts` back (e.g. to compare outputs structurally)
+/// The `total` column must match the available and held funds.
+pub fn read_accounts(reader: impl std::io::Read) -> Result<Vec<Account>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+
+    let mut accounts = vec![];
+    for raw in reader.deserialize() {
+        let raw: RawAccount = raw?;
+        let account = Account {
+            client: raw.client,
+            available: raw.available,
+            held: raw.held,
+            locked: raw.locked,
+        };
+        if account.total() != raw.total {
+            return Err(anyhow!(
+                "Total {} does not match the funds (client = {})",
+                raw.total,
+                raw.client
+            ));
+        }
+        accounts.push(account);
+    }
+    Ok(accounts)
+}
+
+/// Write a reconciliation report with one `metric,amount` row per figure (in CSV format)
+pub fn write_reconciliation(
+    destination: &mut dyn std::io::Write,
+    reconciliation: &Reconciliation,
+) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_writer(destination);
+
+    writer.write_record(["metric", "amount"])?;
+    let rows = [
+        ("opening_total", reconciliation.opening_total),
+        ("total_deposits", reconciliation.total_deposits),
+        ("total_withdrawals", reconciliation.total_withdrawals),
+        ("total_held", reconciliation.total_held),
+        ("total_charged_back", reconciliation.total_charged_back),
+        ("computed_total", reconciliation.computed_total),
+        ("observed_total", reconciliation.observed_total),
+    ];
+    for row in &rows {
+        writer.serialize(row)?;
+    }
+    Ok(())
+}
+
+/// Write a liquidity time series with one `processed,total_available,total_held` row per sample
+pub fn write_liquidity(
+    destination: &mut dyn std::io::Write,
+    samples: &[LiquiditySample],
+) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_writer(destination);
+
+    writer.write_record(["processed", "total_available", "total_held"])?;
+    for sample in samples {
+        writer.serialize((sample.processed, sample.total_available, sample.total_held))?;
+    }
+    Ok(())
+}
+
+/// Write all retained transactions with one `tx,client,amount,state` row each (in CSV format)
+pub fn write_transactions(
+    destination: &mut dyn std::io::Write,
+    transactions: &[StoredTransaction],
+) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().from_writer(destination);
+
+    writer.write_record(["tx", "client", "amount", "state"])?;
+    for stored in transactions {
+        let state = match stored.status {
+            None => "undisputed",
+            Some(DisputeStatus::Disputed) => "disputed",
+            Some(DisputeStatus::Resolved) => "resolved",
+            Some(DisputeStatus::ChargedBack) => "charged_back",
+        };
+        writer.serialize((
+            stored.transaction,
+            stored.client,
+            canonical_amount(stored.amount),
+            state,
+        ))?;
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::tx;
 
-    use rust_decimal_macros::dec;
+    use crate::test_support::amount;
 
     #[test]
     fn empty_list() {
@@ -55,8 +491,8 @@ mod tests {
         let mut buffer = vec![];
         let accounts = vec![Account {
             client: 0,
-            available: dec!(1.0),
-            held: dec!(2.0),
+            available: amount!(1.0),
+            held: amount!(2.0),
             locked: true,
         }];
 
@@ -69,4 +505,557 @@ mod tests {
 "#
         );
     }
+
+    #[test]
+    fn comment_before_header() {
+        let mut buffer = vec![];
+        let accounts = vec![Account {
+            client: 0,
+            available: amount!(1.0),
+            held: amount!(2.0),
+            locked: false,
+        }];
+        let config = WriterConfig {
+            comment: Some("generated by rust-coding-test, 1 accounts".to_string()),
+            ..WriterConfig::default()
+        };
+
+        write_accounts_with_config(&mut buffer, accounts.into_iter(), &config).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            r#"# generated by rust-coding-test, 1 accounts
+client,available,held,total,locked
+0,1.0,2.0,3.0,false
+"#
+        );
+    }
+
+    #[test]
+    fn multi_line_and_empty_comments() {
+        let write = |comment: &str| {
+            let config = WriterConfig {
+                comment: Some(comment.to_string()),
+                always_header: true,
+                ..WriterConfig::default()
+            };
+            let mut buffer = vec![];
+            write_accounts_with_config(&mut buffer, std::iter::empty(), &config).unwrap();
+            String::from_utf8(buffer).unwrap()
+        };
+
+        let header = "client,available,held,total,locked\n";
+        assert_eq!(write(""), format!("#\n{}", header));
+        assert_eq!(
+            write("first\nsecond\r\n\rthird"),
+            format!("# first\n# second\n#\n# third\n{}", header)
+        );
+
+        // a CSV reader that skips comments only sees the header
+        let data = write("first\rsecond");
+        let mut reader = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .from_reader(data.as_bytes());
+        assert_eq!(reader.headers().unwrap().len(), 5);
+        assert_eq!(reader.records().count(), 0);
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn reconciliation_report() {
+        let mut buffer = vec![];
+        let reconciliation = Reconciliation {
+            opening_total: amount!(0),
+            total_deposits: amount!(17.0),
+            total_withdrawals: amount!(2.5),
+            total_held: amount!(4.0),
+            total_charged_back: amount!(3.0),
+            computed_total: amount!(11.5),
+            observed_total: amount!(11.5),
+        };
+
+        write_reconciliation(&mut buffer, &reconciliation).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            r#"metric,amount
+opening_total,0
+total_deposits,17.0
+total_withdrawals,2.5
+total_held,4.0
+total_charged_back,3.0
+computed_total,11.5
+observed_total,11.5
+"#
+        );
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn overdrawn_account() {
+        let accounts = vec![
+            Account {
+                client: 0,
+                available: amount!(-5.5),
+                held: amount!(2.0),
+                locked: false,
+            },
+            Account {
+                client: 1,
+                available: amount!(1.0),
+                held: amount!(0),
+                locked: false,
+            },
+        ];
+
+        let mut buffer = vec![];
+        write_accounts(&mut buffer, accounts.clone().into_iter()).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            r#"client,available,held,total,locked
+0,-5.5,2.0,-3.5,false
+1,1.0,0,1.0,false
+"#
+        );
+
+        let mut buffer = vec![];
+        let config = WriterConfig {
+            overdrawn_column: true,
+            ..WriterConfig::default()
+        };
+        write_accounts_with_config(&mut buffer, accounts.into_iter(), &config).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            r#"client,available,held,total,locked,overdrawn
+0,-5.5,2.0,-3.5,false,true
+1,1.0,0,1.0,false,false
+"#
+        );
+    }
+
+    #[test]
+    fn empty_list_with_header() {
+        let mut buffer = vec![];
+        let accounts = vec![];
+        let config = WriterConfig {
+            always_header: true,
+            ..WriterConfig::default()
+        };
+
+        write_accounts_with_config(&mut buffer, accounts.into_iter(), &config).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(&data, "client,available,held,total,locked\n");
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn mapped_accounts() {
+        let mut buffer = vec![];
+        let accounts = vec![Account {
+            client: 0,
+            available: amount!(1.0),
+            held: amount!(2.0),
+            locked: false,
+        }];
+
+        write_accounts_with_mapper(
+            &mut buffer,
+            accounts.into_iter(),
+            &WriterConfig::default(),
+            |account| Account {
+                held: Amount::default(),
+                ..account
+            },
+        )
+        .unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            "client,available,held,total,locked\n0,1.0,0,1.0,false\n"
+        );
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn liquidity_series() {
+        let mut buffer = vec![];
+        let samples = vec![
+            LiquiditySample {
+                processed: 2,
+                total_available: amount!(8),
+                total_held: amount!(0),
+            },
+            LiquiditySample {
+                processed: 4,
+                total_available: amount!(3),
+                total_held: amount!(5),
+            },
+        ];
+
+        write_liquidity(&mut buffer, &samples).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            "processed,total_available,total_held\n2,8,0\n4,3,5\n"
+        );
+    }
+
+    #[test]
+    fn minor_units() {
+        let config = WriterConfig {
+            minor_units: true,
+            ..WriterConfig::default()
+        };
+
+        let mut buffer = vec![];
+        let accounts = vec![Account {
+            client: 0,
+            available: amount!(1.5),
+            held: amount!(0.0001),
+            locked: false,
+        }];
+        write_accounts_with_config(&mut buffer, accounts.into_iter(), &config).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            "client,available,held,total,locked\n0,15000,1,15001,false\n"
+        );
+
+        let mut buffer = vec![];
+        let accounts = vec![Account {
+            client: 0,
+            available: amount!(1.23456),
+            held: amount!(0),
+            locked: false,
+        }];
+        write_accounts_with_config(&mut buffer, accounts.into_iter(), &config).unwrap_err();
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn negative_zero() {
+        let mut buffer = vec![];
+        let accounts = vec![Account {
+            client: 0,
+            available: -(amount!(1.5) - amount!(1.5)),
+            held: -amount!(0.0000),
+            locked: false,
+        }];
+        assert_eq!(accounts[0].available.to_string(), "-0.0");
+
+        write_accounts(&mut buffer, accounts.into_iter()).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            r#"client,available,held,total,locked
+0,0.0,0.0000,0.0000,false
+"#
+        );
+    }
+
+    #[test]
+    fn transaction_dump() {
+        let mut buffer = vec![];
+        let stored = |transaction, status| StoredTransaction {
+            transaction,
+            client: 3,
+            amount: amount!(1.5),
+            status,
+        };
+        let transactions = vec![
+            stored(tx(1), None),
+            stored(tx(2), Some(DisputeStatus::Disputed)),
+            stored(tx(4), Some(DisputeStatus::Resolved)),
+            stored(tx(5), Some(DisputeStatus::ChargedBack)),
+        ];
+
+        write_transactions(&mut buffer, &transactions).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            r#"tx,client,amount,state
+1,3,1.5,undisputed
+2,3,1.5,disputed
+4,3,1.5,resolved
+5,3,1.5,charged_back
+"#
+        );
+    }
+
+    #[test]
+    fn skip_failed_rows() {
+        let account = |client, available| Account {
+            client,
+            available,
+            held: amount!(0),
+            locked: false,
+        };
+        let accounts = vec![
+            account(0, amount!(1.23456)),
+            account(1, amount!(1)),
+            account(2, amount!(0.00001)),
+            account(3, amount!(2)),
+        ];
+
+        let mut config = WriterConfig {
+            minor_units: true,
+            ..WriterConfig::default()
+        };
+        let mut buffer = vec![];
+        write_accounts_with_config(&mut buffer, accounts.clone().into_iter(), &config).unwrap_err();
+
+        config.skip_failed_rows = true;
+        let mut buffer = vec![];
+        let statistics =
+            write_accounts_with_config(&mut buffer, accounts.into_iter(), &config).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(statistics.skipped, 2);
+        assert_eq!(
+            &data,
+            "client,available,held,total,locked\n1,10000,0,10000,false\n3,20000,0,20000,false\n"
+        );
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn total_overflow() {
+        let accounts = vec![
+            Account {
+                client: 0,
+                available: Amount::MAX,
+                held: amount!(1),
+                locked: false,
+            },
+            Account {
+                client: 1,
+                available: amount!(1),
+                held: amount!(2),
+                locked: false,
+            },
+        ];
+
+        let mut config = WriterConfig::default();
+        let mut buffer = vec![];
+        let error = write_accounts_with_config(&mut buffer, accounts.clone().into_iter(), &config)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Total funds overflow (client = 0)");
+
+        config.skip_failed_rows = true;
+        let mut buffer = vec![];
+        let statistics =
+            write_accounts_with_config(&mut buffer, accounts.into_iter(), &config).unwrap();
+        assert_eq!(statistics.skipped, 1);
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "client,available,held,total,locked\n1,1,2,3,false\n"
+        );
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn precision_loss() {
+        let accounts = vec![
+            Account {
+                client: 0,
+                available: amount!(1.5),
+                held: amount!(0.12345),
+                locked: false,
+            },
+            Account {
+                client: 1,
+                available: amount!(2.25),
+                held: amount!(0),
+                locked: false,
+            },
+        ];
+
+        let mut buffer = vec![];
+        let statistics = write_accounts_with_config(
+            &mut buffer,
+            accounts.clone().into_iter(),
+            &WriterConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(statistics.rounded, 0);
+
+        let config = WriterConfig {
+            round_to_scale: Some(4),
+            warn_precision_loss: true,
+            ..WriterConfig::default()
+        };
+        let mut buffer = vec![];
+        let statistics =
+            write_accounts_with_config(&mut buffer, accounts.into_iter(), &config).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(statistics.rounded, 1);
+        assert_eq!(
+            &data,
+            "client,available,held,total,locked\n0,1.5,0.1235,1.6235,false\n1,2.25,0,2.25,false\n"
+        );
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn canonical_scale() {
+        let config = WriterConfig {
+            canonical_scale: true,
+            ..WriterConfig::default()
+        };
+        let write = |available, held| {
+            let mut buffer = vec![];
+            let accounts = vec![Account {
+                client: 0,
+                available,
+                held,
+                locked: false,
+            }];
+            write_accounts_with_config(&mut buffer, accounts.into_iter(), &config).unwrap();
+            String::from_utf8(buffer).unwrap()
+        };
+
+        assert_eq!(
+            write(amount!(1.0), amount!(0)),
+            "client,available,held,total,locked\n0,1.0000,0.0000,1.0000,false\n"
+        );
+        assert_eq!(
+            write(amount!(1.00), amount!(0.000)),
+            write(amount!(1.0), amount!(0))
+        );
+        assert_eq!(
+            write(amount!(0.12345), amount!(2)),
+            "client,available,held,total,locked\n0,0.12345,2.0000,2.12345,false\n"
+        );
+    }
+
+    #[cfg(feature = "float-amount")]
+    #[test]
+    fn float_format() {
+        let mut buffer = vec![];
+        let accounts = vec![Account {
+            client: 0,
+            available: amount!(0.1) + amount!(0.2),
+            held: amount!(2),
+            locked: false,
+        }];
+
+        // floats have no scale, the precision loss is not hidden but the output is still parseable
+        write_accounts(&mut buffer, accounts.into_iter()).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            "client,available,held,total,locked\n0,0.30000000000000004,2.0,2.3,false\n"
+        );
+        let accounts = read_accounts(data.as_bytes()).unwrap();
+        assert_eq!(accounts[0].available, amount!(0.1) + amount!(0.2));
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn locked_format() {
+        let accounts = vec![
+            Account {
+                client: 0,
+                available: amount!(1),
+                held: amount!(0),
+                locked: true,
+            },
+            Account {
+                client: 1,
+                available: amount!(2),
+                held: amount!(0),
+                locked: false,
+            },
+        ];
+        let write = |locked_format| {
+            let config = WriterConfig {
+                locked_format,
+                ..WriterConfig::default()
+            };
+            let mut buffer = vec![];
+            write_accounts_with_config(&mut buffer, accounts.clone().into_iter(), &config).unwrap();
+            String::from_utf8(buffer).unwrap()
+        };
+
+        assert_eq!(
+            write(BooleanFormat::TrueFalse),
+            "client,available,held,total,locked\n0,1,0,1,true\n1,2,0,2,false\n"
+        );
+        assert_eq!(
+            write(BooleanFormat::OneZero),
+            "client,available,held,total,locked\n0,1,0,1,1\n1,2,0,2,0\n"
+        );
+        assert_eq!(
+            write(BooleanFormat::YesNo),
+            "client,available,held,total,locked\n0,1,0,1,Y\n1,2,0,2,N\n"
+        );
+    }
+
+    #[test]
+    fn read_back() {
+        let accounts = vec![
+            Account {
+                client: 3,
+                available: amount!(1.5),
+                held: amount!(0.25),
+                locked: false,
+            },
+            Account {
+                client: 1,
+                available: amount!(0),
+                held: amount!(2),
+                locked: true,
+            },
+        ];
+
+        let mut buffer = vec![];
+        write_accounts(&mut buffer, accounts.clone().into_iter()).unwrap();
+        assert_eq!(read_accounts(&buffer[..]).unwrap(), accounts);
+
+        assert!(read_accounts(&b""[..]).unwrap().is_empty());
+        let inconsistent = b"client,available,held,total,locked\n1,1,1,3,false\n";
+        read_accounts(&inconsistent[..]).unwrap_err();
+    }
+
+    #[cfg(not(feature = "float-amount"))]
+    #[test]
+    fn negative_total() {
+        let accounts = vec![
+            Account {
+                client: 0,
+                available: amount!(-2),
+                held: amount!(1),
+                locked: false,
+            },
+            Account {
+                client: 1,
+                available: amount!(1),
+                held: amount!(0),
+                locked: false,
+            },
+        ];
+        let write = |negative_total, skip_failed_rows| {
+            let config = WriterConfig {
+                negative_total,
+                skip_failed_rows,
+                ..WriterConfig::default()
+            };
+            let mut buffer = vec![];
+            write_accounts_with_config(&mut buffer, accounts.clone().into_iter(), &config)
+                .map(|statistics| (statistics, String::from_utf8(buffer).unwrap()))
+        };
+
+        let all_rows = "client,available,held,total,locked\n0,-2,1,-1,false\n1,1,0,1,false\n";
+        for policy in [NegativeTotalPolicy::Emit, NegativeTotalPolicy::Flag] {
+            let (statistics, data) = write(policy, false).unwrap();
+            assert_eq!(statistics.negative_totals, 1);
+            assert_eq!(data, all_rows);
+        }
+
+        write(NegativeTotalPolicy::Reject, false).unwrap_err();
+        let (statistics, data) = write(NegativeTotalPolicy::Reject, true).unwrap();
+        assert_eq!(statistics.skipped, 1);
+        assert_eq!(data, "client,available,held,total,locked\n1,1,0,1,false\n");
+    }
 }
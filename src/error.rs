@@ -0,0 +1,200 @@
+use std::fmt;
+
+use crate::types::{Amount, ClientId, DisputeStatus, TransactionId, TransactionType};
+
+/// Specific transaction processing failures that callers may want to tell apart
+///
+/// All other failures are reported as plain `anyhow` errors. These variants travel inside an
+/// `anyhow::Error` as well and can be recovered using `downcast_ref::<TransactionError>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionError {
+    /// A "resolve" names a different client than the disputed transaction belongs to
+    ResolveClientMismatch {
+        transaction: TransactionId,
+        expected: ClientId,
+        found: ClientId,
+    },
+
+    /// A deposit or withdrawal with a negative amount
+    NegativeAmount { transaction: TransactionId },
+
+    /// A deposit or withdrawal with an amount of zero where a positive one is required (from both the
+    /// parser and the `Transaction::deposit`/`Transaction::withdrawal` constructors)
+    ZeroAmount { transaction: TransactionId },
+
+    /// A deposit or withdrawal with more decimal places than allowed
+    ExcessiveScale {
+        transaction: TransactionId,
+        max_scale: u32,
+    },
+
+    /// A deposit or withdrawal with an amount above the allowed maximum
+    AmountOutOfRange {
+        transaction: TransactionId,
+        max_amount: Amount,
+    },
+
+    /// A "dispute", "resolve", or "chargeback" references a withdrawal, which cannot be disputed
+    NotDisputable { transaction: TransactionId },
+
+    /// A "dispute", "resolve", or "chargeback" references a transaction that was never seen (or
+    /// not stored due to the store limit)
+    TransactionNotFound { transaction: TransactionId },
+
+    /// A transaction for a client ID that is configured as reserved
+    ReservedClient { client: ClientId },
+
+    /// A transaction of a type that is not enabled in the handler
+    TransactionTypeDisabled { transaction_type: TransactionType },
+
+    /// A dispute for an account that has been locked by a chargeback in a previous run
+    AccountFrozen {
+        client: ClientId,
+        transaction: TransactionId,
+    },
+
+    /// A dispute for an account that has been locked by the chargeback `prior_transaction`
+    FrozenByChargeback {
+        client: ClientId,
+        transaction: TransactionId,
+
+        /// Boxed, a third inline ID would make every `Result` carrying this error too large with
+        /// the `string-tx-id` feature
+        prior_transaction: Box<TransactionId>,
+    },
+
+    /// A deposit or withdrawal reusing the ID of an earlier one of the other kind
+    DuplicateTransaction { transaction: TransactionId },
+
+    /// A dispute of a client that has already opened the maximum number of disputes
+    DisputeRateLimited {
+        client: ClientId,
+        transaction: TransactionId,
+        max_disputes: usize,
+    },
+
+    /// A "resolve" or "chargeback" for a transaction whose last dispute has already ended
+    NotCurrentlyDisputed {
+        transaction: TransactionId,
+        status: DisputeStatus,
+    },
+
+    /// A dispute of a deposit above the configured maximum dispute amount
+    DisputeAmountExceeded {
+        transaction: TransactionId,
+        max_amount: Amount,
+    },
+
+    /// A client ID in the input that does not fit into `ClientId`
+    ClientIdOutOfRange {
+        client: u64,
+        transaction: TransactionId,
+    },
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::ResolveClientMismatch {
+                transaction,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Mismatching client for resolve (tx = {}, expected client = {}, found client = {})",
+                transaction, expected, found
+            ),
+            TransactionError::NegativeAmount { transaction } => {
+                write!(f, "Negative amount (tx = {})", transaction)
+            }
+            TransactionError::ZeroAmount { transaction } => {
+                write!(f, "Zero amount (tx = {})", transaction)
+            }
+            TransactionError::ExcessiveScale {
+                transaction,
+                max_scale,
+            } => write!(
+                f,
+                "Amount has more than {} decimal places (tx = {})",
+                max_scale, transaction
+            ),
+            TransactionError::AmountOutOfRange {
+                transaction,
+                max_amount,
+            } => write!(
+                f,
+                "Amount exceeds the maximum of {} (tx = {})",
+                max_amount, transaction
+            ),
+            TransactionError::NotDisputable { transaction } => {
+                write!(f, "Transaction is not disputable (tx = {})", transaction)
+            }
+            TransactionError::TransactionNotFound { transaction } => {
+                write!(f, "Transaction not found (tx = {})", transaction)
+            }
+            TransactionError::ReservedClient { client } => {
+                write!(f, "Client ID is reserved (client = {})", client)
+            }
+            TransactionError::TransactionTypeDisabled { transaction_type } => {
+                write!(f, "Transaction type '{}' is disabled", transaction_type)
+            }
+            TransactionError::AccountFrozen {
+                client,
+                transaction,
+            } => write!(
+                f,
+                "Account is frozen after a chargeback (client = {}, tx = {})",
+                client, transaction
+            ),
+            TransactionError::FrozenByChargeback {
+                client,
+                transaction,
+                prior_transaction,
+            } => write!(
+                f,
+                "Account is frozen after the chargeback of tx {} (client = {}, tx = {})",
+                prior_transaction, client, transaction
+            ),
+            TransactionError::DuplicateTransaction { transaction } => {
+                write!(f, "Transaction ID is already in use (tx = {})", transaction)
+            }
+            TransactionError::DisputeRateLimited {
+                client,
+                transaction,
+                max_disputes,
+            } => write!(
+                f,
+                "Client has already opened {} disputes (client = {}, tx = {})",
+                max_disputes, client, transaction
+            ),
+            TransactionError::NotCurrentlyDisputed {
+                transaction,
+                status,
+            } => write!(
+                f,
+                "Transaction is not currently disputed (tx = {}, status = {:?})",
+                transaction, status
+            ),
+            TransactionError::DisputeAmountExceeded {
+                transaction,
+                max_amount,
+            } => write!(
+                f,
+                "Disputed amount exceeds the maximum of {} (tx = {})",
+                max_amount, transaction
+            ),
+            TransactionError::ClientIdOutOfRange {
+                client,
+                transaction,
+            } => write!(
+                f,
+                "Client ID {} exceeds the maximum of {}, a wider `ClientId` type is needed (tx = {})",
+                client,
+                ClientId::MAX,
+                transaction
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
@@ -0,0 +1,219 @@
+use anyhow::Result;
+
+use crate::types::{Account, Amount};
+
+/// Locale-like settings for rendering amounts in the human format
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumberFormat {
+    pub decimal_separator: char,
+
+    /// Inserted between groups of three integer digits
+    pub grouping_separator: char,
+
+    /// Number of decimal places shown for every amount
+    pub scale: usize,
+}
+
+/// English-style, e.g. `1,234,567.8900`
+impl Default for NumberFormat {
+    fn default() -> Self {
+        Self {
+            decimal_separator: '.',
+            grouping_separator: ',',
+            scale: 4,
+        }
+    }
+}
+
+/// Render an amount with thousands separators and a fixed scale, e.g. `1,234,567.8900`
+pub fn format_amount(amount: Amount) -> String {
+    format_amount_with(amount, &NumberFormat::default())
+}
+
+/// Render an amount using the given separators and scale
+pub fn format_amount_with(amount: Amount, format: &NumberFormat) -> String {
+    let plain = format!("{:.*}", format.scale, amount);
+    let (sign, digits) = match plain.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", plain.as_str()),
+    };
+    let (integer, fraction) = digits.split_at(digits.find('.').unwrap_or(digits.len()));
+
+    let mut grouped = String::new();
+    for (index, digit) in integer.chars().enumerate() {
+        if index > 0 && (integer.len() - index) % 3 == 0 {
+            grouped.push(format.grouping_separator);
+        }
+        grouped.push(digit);
+    }
+    if let Some(fraction) = fraction.strip_prefix('.') {
+        grouped.push(format.decimal_separator);
+        grouped.push_str(fraction);
+    }
+    format!("{}{}", sign, grouped)
+}
+
+/// Write all accounts as an aligned table for operators (not meant to be machine-readable)
+///
+/// All accounts are buffered to compute the column widths.
+pub fn write_accounts_human(
+    destination: &mut dyn std::io::Write,
+    accounts: impl Iterator<Item = Account>,
+) -> Result<()> {
+    write_accounts_human_with_format(destination, accounts, &NumberFormat::default())
+}
+
+/// Like `write_accounts_human`, but render the amounts using the given format
+pub fn write_accounts_human_with_format(
+    destination: &mut dyn std::io::Write,
+    accounts: impl Iterator<Item = Account>,
+    format: &NumberFormat,
+) -> Result<()> {
+    write_table(
+        destination,
+        accounts,
+        |amount| format_amount_with(amount, format),
+        "  ",
+    )
+}
+
+/// Write all accounts as CSV with the columns padded to the same width
+///
+/// The values are the same as in the plain CSV output, so the result can still be read as CSV
+/// (with whitespace trimming). All accounts are buffered to compute the column widths.
+pub fn write_accounts_pretty(
+    destination: &mut dyn std::io::Write,
+    accounts: impl Iterator<Item = Account>,
+) -> Result<()> {
+    write_table(destination, accounts, |amount| amount.to_string(), ", ")
+}
+
+/// Write the accounts as right-aligned columns joined by `separator`
+fn write_table(
+    destination: &mut dyn std::io::Write,
+    accounts: impl Iterator<Item = Account>,
+    format_amount: impl Fn(Amount) -> String,
+    separator: &str,
+) -> Result<()> {
+    let header = ["client", "available", "held", "total", "locked"];
+    let rows: Vec<[String; 5]> = accounts
+        .map(|account| {
+            [
+                account.client.to_string(),
+                format_amount(account.available),
+                format_amount(account.held),
+                format_amount(account.total()),
+                account.locked.to_string(),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let header = header.map(str::to_string);
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+            .collect();
+        writeln!(destination, "{}", cells.join(separator))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::amount;
+
+    #[test]
+    fn amount_formatting() {
+        assert_eq!(format_amount(amount!(0)), "0.0000");
+        assert_eq!(format_amount(amount!(123.45)), "123.4500");
+        assert_eq!(format_amount(amount!(1234)), "1,234.0000");
+        assert_eq!(format_amount(amount!(1234567.89)), "1,234,567.8900");
+        assert_eq!(format_amount(amount!(-123456.7)), "-123,456.7000");
+    }
+
+    #[test]
+    fn european_amount_formatting() {
+        let format = NumberFormat {
+            decimal_separator: ',',
+            grouping_separator: '.',
+            scale: 1,
+        };
+        assert_eq!(format_amount_with(amount!(1234.5), &format), "1.234,5");
+        assert_eq!(
+            format_amount_with(amount!(-1234567), &format),
+            "-1.234.567,0"
+        );
+
+        let integer = NumberFormat { scale: 0, ..format };
+        assert_eq!(format_amount_with(amount!(1234.4), &integer), "1.234");
+    }
+
+    #[test]
+    fn large_balance_table() {
+        let mut buffer = vec![];
+        let accounts = vec![
+            Account {
+                client: 1,
+                available: amount!(1234567.89),
+                held: amount!(1000),
+                locked: false,
+            },
+            Account {
+                client: 42,
+                available: amount!(0.5),
+                held: amount!(0),
+                locked: true,
+            },
+        ];
+
+        write_accounts_human(&mut buffer, accounts.into_iter()).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            r#"client       available        held           total  locked
+     1  1,234,567.8900  1,000.0000  1,235,567.8900   false
+    42          0.5000      0.0000          0.5000    true
+"#
+        );
+    }
+
+    #[test]
+    fn pretty_table() {
+        let mut buffer = vec![];
+        let accounts = vec![
+            Account {
+                client: 1,
+                available: amount!(1234.5),
+                held: amount!(0),
+                locked: false,
+            },
+            Account {
+                client: 42,
+                available: amount!(0.5),
+                held: amount!(10),
+                locked: true,
+            },
+        ];
+
+        write_accounts_pretty(&mut buffer, accounts.into_iter()).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        assert_eq!(
+            &data,
+            r#"client, available, held,  total, locked
+     1,    1234.5,    0, 1234.5,  false
+    42,       0.5,   10,   10.5,   true
+"#
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::transaction_handler::{Rejection, Statistics};
+
+/// Counts and details of all rejected transactions for programmatic post-run analysis
+#[derive(Debug, Serialize)]
+struct ErrorSummary<'a> {
+    applied: usize,
+    rejected: usize,
+    rejections: &'a [Rejection],
+}
+
+/// Write a summary of the rejected transactions to the provided destination (as a JSON object)
+///
+/// The `rejections` may be empty (e.g. if they have not been collected) while the counts still
+/// cover all transactions.
+pub fn write_error_summary(
+    destination: &mut dyn std::io::Write,
+    statistics: &Statistics,
+    rejections: &[Rejection],
+) -> Result<()> {
+    let summary = ErrorSummary {
+        applied: statistics.applied,
+        rejected: statistics.rejected,
+        rejections,
+    };
+    serde_json::to_writer(&mut *destination, &summary)?;
+    writeln!(destination)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::tx;
+    use crate::types::TransactionType;
+
+    #[test]
+    fn summary_shape() {
+        let mut buffer = vec![];
+        let statistics = Statistics {
+            applied: 3,
+            rejected: 2,
+        };
+        let rejections = vec![
+            Rejection {
+                position: 2,
+                transaction_type: None,
+                client: None,
+                transaction: None,
+                error: "Invalid row".to_string(),
+            },
+            Rejection {
+                position: 5,
+                transaction_type: Some(TransactionType::Withdrawal),
+                client: Some(1),
+                transaction: Some(tx(4)),
+                error: "Insufficient funds".to_string(),
+            },
+        ];
+
+        write_error_summary(&mut buffer, &statistics, &rejections).unwrap();
+        let data = String::from_utf8(buffer).unwrap();
+        // string IDs are written as JSON strings
+        let id = if cfg!(feature = "string-tx-id") {
+            r#""4""#
+        } else {
+            "4"
+        };
+        assert_eq!(
+            data,
+            format!(
+                concat!(
+                    r#"{{"applied":3,"rejected":2,"rejections":["#,
+                    r#"{{"position":2,"type":null,"client":null,"tx":null,"error":"Invalid row"}},"#,
+                    r#"{{"position":5,"type":"withdrawal","client":1,"tx":{},"error":"Insufficient funds"}}"#,
+                    "]}}\n"
+                ),
+                id
+            )
+        );
+    }
+}
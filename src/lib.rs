@@ -6,10 +6,20 @@
 #[macro_use]
 extern crate log;
 
-mod account_store;
-mod transaction_store;
-
+pub mod account_store;
+#[cfg(feature = "append-log")]
+pub mod append_log_store;
+#[cfg(feature = "arrow")]
+pub mod arrow_writer;
 pub mod csv_parser;
 pub mod csv_writer;
+pub mod error;
+pub mod human_writer;
+pub mod json_writer;
+pub mod reference_validator;
+pub mod serde_writer;
+#[cfg(test)]
+mod test_support;
 pub mod transaction_handler;
+pub mod transaction_store;
 pub mod types;
@@ -1,43 +1,610 @@
-#![forbid(unsafe_code)]
+#![cfg_attr(not(feature = "mmap"), forbid(unsafe_code))]
+#![cfg_attr(feature = "mmap", deny(unsafe_code))] // only allowed for memory-mapping the input
 
-use anyhow::{anyhow, Result};
-use pretty_env_logger;
+use anyhow::{anyhow, Context, Result};
 
 use rust_coding_test::{
-    csv_parser::iter_transactions, csv_writer::write_accounts,
-    transaction_handler::TransactionHandler,
+    csv_parser::iter_transactions,
+    csv_writer::{
+        read_accounts, write_accounts_with_config, write_liquidity, write_reconciliation,
+        write_transactions, WriterConfig,
+    },
+    human_writer::{write_accounts_human, write_accounts_pretty},
+    json_writer::write_error_summary,
+    reference_validator::{ClientOrder, ClientOrderCheck},
+    transaction_handler::{HandlerConfig, Statistics, TransactionHandler},
+    types::{Account, ClientId},
 };
 
-/// Read records in CSV format from the `source`, process all transactions and write the account
-/// data to `destination` (also in CSV format)
+/// Presentation of the account data on stdout
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum OutputFormat {
+    #[default]
+    Csv,
+    Human,
+    Pretty,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "csv" => Ok(OutputFormat::Csv),
+            "human" => Ok(OutputFormat::Human),
+            "pretty" => Ok(OutputFormat::Pretty),
+            _ => Err(anyhow!("Unknown output format '{}'", format)),
+        }
+    }
+}
+
+/// Settings taken from the command line
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    /// Path of the input CSV file
+    input: String,
+
+    /// Process all files matching this pattern (in sorted order) instead of `input`
+    input_glob: Option<String>,
+
+    /// Log a warning for a completely empty (zero-byte) input instead of silently succeeding
+    warn_empty_input: bool,
+
+    /// Abort with an error if the input has more rows than this
+    max_rows: Option<usize>,
+
+    /// Split the output into this many files (by client ID modulo the number of shards)
+    shards: Option<usize>,
+
+    /// Sharded output is written to `<output_prefix>-<shard>.csv`
+    output_prefix: Option<String>,
+
+    /// Write a reconciliation report (in CSV format) to this path
+    reconcile_report: Option<String>,
+
+    /// How accounts are written to stdout
+    format: OutputFormat,
+
+    /// Warn if the fraction of rejected rows is larger than this (between 0 and 1)
+    max_rejected_ratio: Option<f64>,
+
+    /// Fail instead of only warning if `max_rejected_ratio` is exceeded
+    fail_on_rejected_ratio: bool,
+
+    /// Start with the accounts from this file (in the account output format)
+    seed_accounts: Option<String>,
+
+    /// Abort if the transactions of the input are not in this client order
+    client_order: Option<ClientOrder>,
+
+    /// Also write the accounts to this path (in the same format as on stdout)
+    tee: Option<String>,
+
+    /// Write the SHA-256 checksum of the account output to this path (`-` for stderr)
+    /// The accounts are sorted by client ID for this, so that the checksum is stable.
+    checksum: Option<String>,
+
+    /// Write a summary of all rejected transactions (in JSON format) to this path
+    error_summary: Option<String>,
+
+    /// Write all retained transactions with their dispute state (in CSV format) to this path
+    dump_transactions: Option<String>,
+
+    /// Sample the system liquidity every this many transactions
+    liquidity_interval: Option<usize>,
+
+    /// Write the liquidity samples (in CSV format) to this path
+    liquidity_report: Option<String>,
+
+    /// Only output the accounts of these clients
+    clients: Option<Vec<ClientId>>,
+
+    /// Write amounts as integers scaled by 10^4 (CSV and sharded output only)
+    minor_units: bool,
+
+    /// Add a column with the number of currently disputed transactions (CSV and sharded output only)
+    disputed_column: bool,
+}
+
+impl Options {
+    /// Parse the command line arguments (without the executable name)
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = Options::default();
+        let mut input = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--max-rows" => options.max_rows = Some(parse_value(&arg, args.next())?),
+                "--warn-empty-input" => options.warn_empty_input = true,
+                "--shards" => options.shards = Some(parse_value(&arg, args.next())?),
+                "--output-prefix" => options.output_prefix = Some(parse_value(&arg, args.next())?),
+                "--format" => options.format = parse_value(&arg, args.next())?,
+                "--pretty" => options.format = OutputFormat::Pretty,
+                "--minor-units" => options.minor_units = true,
+                "--disputed-column" => options.disputed_column = true,
+                "--max-rejected-ratio" => {
+                    options.max_rejected_ratio = Some(parse_value(&arg, args.next())?)
+                }
+                "--fail-on-rejected-ratio" => options.fail_on_rejected_ratio = true,
+                "--reconcile-report" => {
+                    options.reconcile_report = Some(parse_value(&arg, args.next())?)
+                }
+                "--seed-accounts" => options.seed_accounts = Some(parse_value(&arg, args.next())?),
+                "--client-order" => options.client_order = Some(parse_value(&arg, args.next())?),
+                "--tee" => options.tee = Some(parse_value(&arg, args.next())?),
+                "--checksum" => options.checksum = Some(parse_value(&arg, args.next())?),
+                "--error-summary" => options.error_summary = Some(parse_value(&arg, args.next())?),
+                "--dump-transactions" => {
+                    options.dump_transactions = Some(parse_value(&arg, args.next())?)
+                }
+                "--liquidity-interval" => {
+                    options.liquidity_interval = Some(parse_value(&arg, args.next())?)
+                }
+                "--liquidity-report" => {
+                    options.liquidity_report = Some(parse_value(&arg, args.next())?)
+                }
+                "--clients" => options.clients = Some(parse_list(&arg, args.next())?),
+                "--input-glob" => options.input_glob = Some(parse_value(&arg, args.next())?),
+                _ if arg.starts_with("--") => return Err(anyhow!("Unknown option '{}'", arg)),
+                _ => {
+                    if input.replace(arg).is_some() {
+                        return Err(anyhow!("Only a single input file is supported"));
+                    }
+                }
+            }
+        }
+
+        match (input, &options.input_glob) {
+            (Some(input), None) => options.input = input,
+            (None, Some(_)) => {}
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "Either an input file or '--input-glob' can be given"
+                ))
+            }
+            (None, None) => return Err(anyhow!("Missing input file")),
+        }
+
+        match (options.shards, &options.output_prefix) {
+            (Some(0), _) => return Err(anyhow!("At least one shard is required")),
+            (Some(_), None) => return Err(anyhow!("'--shards' requires '--output-prefix'")),
+            (None, Some(_)) => return Err(anyhow!("'--output-prefix' requires '--shards'")),
+            _ => {}
+        }
+
+        if let Some(ratio) = options.max_rejected_ratio {
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err(anyhow!(
+                    "'--max-rejected-ratio' must be between 0 and 1, got {}",
+                    ratio
+                ));
+            }
+        }
+
+        if options.shards.is_some() && options.checksum.is_some() {
+            return Err(anyhow!("'--checksum' cannot be combined with '--shards'"));
+        }
+
+        match (options.liquidity_interval, &options.liquidity_report) {
+            (Some(0), _) => return Err(anyhow!("The liquidity interval must be positive")),
+            (Some(_), None) => {
+                return Err(anyhow!(
+                    "'--liquidity-interval' requires '--liquidity-report'"
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(anyhow!(
+                    "'--liquidity-report' requires '--liquidity-interval'"
+                ))
+            }
+            _ => {}
+        }
+        Ok(options)
+    }
+}
+
+/// Parse the value following the command line option `name`
+fn parse_value<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<T> {
+    let value = value.ok_or_else(|| anyhow!("Missing value for '{}'", name))?;
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid value '{}' for '{}'", value, name))
+}
+
+/// Parse the comma-separated values following the command line option `name`
+fn parse_list<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<Vec<T>> {
+    let value: String = parse_value(name, value)?;
+    value
+        .split(',')
+        .map(|item| parse_value(name, Some(item.trim().to_string())))
+        .collect()
+}
+
+/// Wrap the `reader` of the file at `path` into a decoder if the file extension asks for it
+fn decode_input(
+    path: &str,
+    reader: impl std::io::Read + 'static,
+) -> Result<Box<dyn std::io::Read>> {
+    if path.ends_with(".zst") {
+        #[cfg(feature = "zstd")]
+        return Ok(Box::new(zstd::Decoder::new(reader)?));
+
+        #[cfg(not(feature = "zstd"))]
+        return Err(anyhow!("Reading '.zst' files requires the 'zstd' feature"));
+    }
+    Ok(Box::new(reader))
+}
+
+/// Open the input file at `path` (memory-mapped if the `mmap` feature is enabled)
+fn open_input(path: &str) -> Result<Box<dyn std::io::Read>> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Cannot open input file '{}'", path))?;
+
+    #[cfg(feature = "mmap")]
+    return decode_input(path, std::io::Cursor::new(map_file(&file)?));
+
+    #[cfg(not(feature = "mmap"))]
+    return decode_input(path, file);
+}
+
+/// Paths of all files matching the `pattern`, sorted by name
+#[cfg(feature = "glob")]
+fn expand_glob(pattern: &str) -> Result<Vec<String>> {
+    let mut paths = vec![];
+    for path in glob::glob(pattern)? {
+        let path = path?;
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid file name '{}'", path.display()))?;
+        paths.push(path.to_string());
+    }
+    if paths.is_empty() {
+        return Err(anyhow!("No input file matches '{}'", pattern));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(not(feature = "glob"))]
+fn expand_glob(_pattern: &str) -> Result<Vec<String>> {
+    Err(anyhow!("'--input-glob' requires the 'glob' feature"))
+}
+
+/// Map the whole `file` into memory
+#[cfg(feature = "mmap")]
+#[allow(unsafe_code)]
+fn map_file(file: &std::fs::File) -> Result<memmap2::Mmap> {
+    // Safety: The mapping is only ever read. The input file must not be modified while it is being
+    // processed, which would already produce garbage when reading it the regular way.
+    Ok(unsafe { memmap2::Mmap::map(file)? })
+}
+
+/// Whether the `source` has no data at all (not even a header)
+fn is_empty_input(source: &mut impl std::io::BufRead) -> bool {
+    source.fill_buf().is_ok_and(|buffer| buffer.is_empty())
+}
+
+/// Open the input file at `path` like `open_input`, with `--warn-empty-input` a warning names the
+/// file if it is completely empty
+fn open_checked_input(
+    path: &str,
+    options: &Options,
+) -> Result<std::io::BufReader<Box<dyn std::io::Read>>> {
+    let mut source = std::io::BufReader::new(open_input(path)?);
+    if options.warn_empty_input && is_empty_input(&mut source) {
+        log::warn!(
+            "Input '{}' is empty (0 bytes), this often indicates an upstream failure",
+            path
+        );
+    }
+    Ok(source)
+}
+
+/// Read records in CSV format from the `sources` (one after the other, each with its own header)
+/// and process all transactions with the same handler
+fn handle_input(
+    sources: impl IntoIterator<Item = impl std::io::Read>,
+    options: &Options,
+) -> Result<TransactionHandler> {
+    let mut order_check = options.client_order.map(ClientOrderCheck::new);
+    let mut order_violation = None;
+    let mut transactions = sources
+        .into_iter()
+        .flat_map(iter_transactions)
+        .enumerate()
+        .map_while(|(position, transaction)| {
+            if let (Some(check), Ok(transaction)) = (&mut order_check, &transaction) {
+                if let Err(error) = check.check(position, transaction.client()) {
+                    order_violation = Some(error);
+                    return None; // stop processing right away
+                }
+            }
+            Some(transaction)
+        })
+        .fuse(); // the `max_rows` check must not read on after an order violation
+
+    let mut handler = TransactionHandler::with_config(HandlerConfig {
+        liquidity_sample_interval: options.liquidity_interval,
+        collect_rejections: options.error_summary.is_some(),
+        ..HandlerConfig::default()
+    });
+    if let Some(path) = &options.seed_accounts {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Cannot open seed file '{}'", path))?;
+        read_accounts(file)
+            .and_then(|accounts| handler.open_accounts(accounts))
+            .with_context(|| format!("Cannot seed accounts from '{}'", path))?;
+    }
+
+    match options.max_rows {
+        Some(max_rows) => {
+            handler.handle_transactions(transactions.by_ref().take(max_rows));
+            if transactions.next().is_some() {
+                return Err(anyhow!("Input exceeds the maximum of {} rows", max_rows));
+            }
+        }
+        None => handler.handle_transactions(transactions),
+    }
+
+    match order_violation {
+        Some(error) => Err(error),
+        None => Ok(handler),
+    }
+}
+
+/// Warn (or fail) if too many rows have been rejected, which hints at a malformed input feed
+/// Returns whether the configured threshold has been exceeded.
+fn check_rejected_ratio(statistics: &Statistics, options: &Options) -> Result<bool> {
+    let threshold = match options.max_rejected_ratio {
+        Some(threshold) => threshold,
+        None => return Ok(false),
+    };
+
+    let ratio = statistics.rejected_ratio();
+    if ratio <= threshold {
+        return Ok(false);
+    }
+
+    let message = format!(
+        "{} of {} rows ({:.1}%) have been rejected, the threshold is {:.1}%",
+        statistics.rejected,
+        statistics.applied + statistics.rejected,
+        ratio * 100.0,
+        threshold * 100.0
+    );
+    if options.fail_on_rejected_ratio {
+        return Err(anyhow!(message));
+    }
+    log::warn!("{}", message);
+    Ok(true)
+}
+
+/// Read records in CSV format from the `sources`, process all transactions and write the account
+/// data to `destination` (also in CSV format) or the shard files, plus any requested reports
 fn process_transactions(
-    source: impl std::io::Read,
+    sources: impl IntoIterator<Item = impl std::io::Read>,
+    destination: &mut dyn std::io::Write,
+    options: &Options,
+) -> Result<()> {
+    let mut handler = handle_input(sources, options)?;
+    check_rejected_ratio(handler.statistics(), options)?;
+
+    if let Some(path) = &options.reconcile_report {
+        let mut file = std::fs::File::create(path)?;
+        write_reconciliation(&mut file, &handler.reconcile())?;
+    }
+
+    if let Some(path) = &options.error_summary {
+        let mut file = std::fs::File::create(path)?;
+        write_error_summary(&mut file, handler.statistics(), handler.rejections())?;
+    }
+
+    if let Some(path) = &options.dump_transactions {
+        let mut file = std::fs::File::create(path)?;
+        write_transactions(&mut file, &handler.transaction_audit())?;
+    }
+
+    if let Some(path) = &options.liquidity_report {
+        let mut file = std::fs::File::create(path)?;
+        write_liquidity(&mut file, handler.liquidity_series())?;
+    }
+
+    let config = WriterConfig {
+        minor_units: options.minor_units,
+        disputed_counts: options.disputed_column.then(|| handler.open_disputes()),
+        ..WriterConfig::default()
+    };
+
+    let accounts = handler.into_iter().filter(|account| {
+        options
+            .clients
+            .as_ref()
+            .is_none_or(|clients| clients.contains(&account.client))
+    });
+
+    match (options.shards, &options.output_prefix, &options.checksum) {
+        (Some(shards), Some(prefix), _) => write_shards(accounts, shards, prefix, &config),
+        (_, _, Some(target)) => {
+            let mut accounts: Vec<_> = accounts.collect();
+            accounts.sort_by_key(|account| account.client);
+            let checksum = write_with_checksum(destination, |writer| {
+                write_output(writer, accounts.into_iter(), options.format, &config)
+            })?;
+
+            if target == "-" {
+                eprintln!("{}", checksum);
+            } else {
+                std::fs::write(target, format!("{}\n", checksum))?;
+            }
+            Ok(())
+        }
+        _ => write_output(destination, accounts, options.format, &config),
+    }
+}
+
+/// Write the accounts to `destination` in the given `format`
+fn write_output(
     destination: &mut dyn std::io::Write,
+    accounts: impl Iterator<Item = Account>,
+    format: OutputFormat,
+    config: &WriterConfig,
 ) -> Result<()> {
-    let transactions = iter_transactions(source);
+    match format {
+        OutputFormat::Csv => {
+            write_accounts_with_config(destination, accounts, config)?;
+            Ok(())
+        }
+        OutputFormat::Human => write_accounts_human(destination, accounts),
+        OutputFormat::Pretty => write_accounts_pretty(destination, accounts),
+    }
+}
+
+/// Passes everything on to its writer while feeding it into a SHA-256 hasher
+#[cfg(feature = "checksum")]
+struct HashingWriter<'a> {
+    inner: &'a mut dyn std::io::Write,
+    hasher: sha2::Sha256,
+}
+
+#[cfg(feature = "checksum")]
+impl std::io::Write for HashingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        use sha2::Digest;
 
-    let mut handler = TransactionHandler::new();
-    handler.handle_transactions(transactions);
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
 
-    write_accounts(destination, handler.into_iter())
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Let `write` write to `destination` and return the SHA-256 checksum (in hex) of all its output
+#[cfg(feature = "checksum")]
+fn write_with_checksum(
+    destination: &mut dyn std::io::Write,
+    write: impl FnOnce(&mut dyn std::io::Write) -> Result<()>,
+) -> Result<String> {
+    use sha2::Digest;
+
+    let mut writer = HashingWriter {
+        inner: destination,
+        hasher: sha2::Sha256::new(),
+    };
+    write(&mut writer)?;
+    Ok(writer
+        .hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(not(feature = "checksum"))]
+fn write_with_checksum(
+    _destination: &mut dyn std::io::Write,
+    _write: impl FnOnce(&mut dyn std::io::Write) -> Result<()>,
+) -> Result<String> {
+    Err(anyhow!("'--checksum' requires the 'checksum' feature"))
+}
+
+/// Writes everything to all of its writers (e.g. stdout and a file)
+struct Tee<'a>(Vec<&'a mut dyn std::io::Write>);
+
+impl std::io::Write for Tee<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for writer in &mut self.0 {
+            writer.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        for writer in &mut self.0 {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Distribute the accounts over `shards` groups by client ID, each group is sorted by client ID
+/// The result does not depend on the (randomized) iteration order of the accounts, so the sharded
+/// output is byte-identical across runs.
+fn shard_accounts(accounts: impl Iterator<Item = Account>, shards: usize) -> Vec<Vec<Account>> {
+    let mut sharded = vec![vec![]; shards];
+    for account in accounts {
+        sharded[account.client as usize % shards].push(account);
+    }
+    for shard in &mut sharded {
+        shard.sort_by_key(|account| account.client);
+    }
+    sharded
+}
+
+/// Write the accounts into one CSV file per shard, named `<prefix>-<shard>.csv`
+fn write_shards(
+    accounts: impl Iterator<Item = Account>,
+    shards: usize,
+    prefix: &str,
+    config: &WriterConfig,
+) -> Result<()> {
+    for (shard, accounts) in shard_accounts(accounts, shards).into_iter().enumerate() {
+        let mut file = std::fs::File::create(format!("{}-{}.csv", prefix, shard))?;
+        write_accounts_with_config(&mut file, accounts.into_iter(), config)?;
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
     pretty_env_logger::init();
 
-    let path = std::env::args()
-        .nth(1) // skip executable name
-        .ok_or_else(|| anyhow!("Missing input file"))?;
+    let options = Options::parse(std::env::args().skip(1))?; // skip executable name
 
-    let file = std::fs::File::open(path)?;
+    let paths = match &options.input_glob {
+        Some(pattern) => expand_glob(pattern)?,
+        None => vec![options.input.clone()],
+    };
+    let sources = paths
+        .iter()
+        .map(|path| open_checked_input(path, &options))
+        .collect::<Result<Vec<_>>>()?;
     let mut stdout = Box::new(std::io::stdout());
-    process_transactions(file, &mut stdout)
+    match &options.tee {
+        Some(path) => {
+            let mut file = std::fs::File::create(path)?;
+            let mut tee = Tee(vec![&mut stdout, &mut file]);
+            process_transactions(sources, &mut tee, &options)?;
+            std::io::Write::flush(&mut tee)?;
+            Ok(())
+        }
+        None => process_transactions(sources, &mut stdout, &options),
+    }
 }
 
+#[cfg(test)]
+#[path = "test_support/logger.rs"]
+mod test_logger;
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_logger::capture_logs;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// The amount for `text` (as exact as the type backing `Amount` allows)
+    fn amount(text: &str) -> rust_coding_test::types::Amount {
+        text.parse().unwrap()
+    }
 
+    #[cfg(not(feature = "float-amount"))] // compares the exact decimal output
     #[test]
     fn modified_example_from_requirements() {
         // Note: This test makes assumption about exact output format and is therefore very brittle.
@@ -51,7 +618,7 @@ withdrawal, 1, 4, 1.5
 "#;
         let mut destination = vec![];
 
-        process_transactions(&source[..], &mut destination).unwrap();
+        process_transactions([&source[..]], &mut destination, &Options::default()).unwrap();
 
         let result = String::from_utf8(destination).unwrap();
         assert_eq!(
@@ -61,4 +628,579 @@ withdrawal, 1, 4, 1.5
 "#
         );
     }
+
+    #[test]
+    fn header_only_input() {
+        // Empty in, empty out: not even the output header is written
+        let source = br#"
+type, client, tx, amount
+"#;
+        let mut destination = vec![];
+
+        process_transactions([&source[..]], &mut destination, &Options::default()).unwrap();
+        assert!(destination.is_empty());
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = Options::parse(args(&["input.csv", "--max-rows", "10"])).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                input: "input.csv".to_string(),
+                max_rows: Some(10),
+                ..Options::default()
+            }
+        );
+
+        let options = Options::parse(args(&[
+            "input.csv",
+            "--shards",
+            "4",
+            "--output-prefix",
+            "out",
+        ]))
+        .unwrap();
+        assert_eq!(options.shards, Some(4));
+        assert_eq!(options.output_prefix, Some("out".to_string()));
+
+        let options = Options::parse(args(&["input.csv", "--format", "human"])).unwrap();
+        assert_eq!(options.format, OutputFormat::Human);
+
+        let options = Options::parse(args(&["input.csv", "--pretty"])).unwrap();
+        assert_eq!(options.format, OutputFormat::Pretty);
+
+        let options = Options::parse(args(&["input.csv", "--minor-units"])).unwrap();
+        assert!(options.minor_units);
+
+        let options = Options::parse(args(&["input.csv", "--clients", "1, 5,9"])).unwrap();
+        assert_eq!(options.clients, Some(vec![1, 5, 9]));
+
+        let options = Options::parse(args(&["input.csv", "--dump-transactions", "t.csv"])).unwrap();
+        assert_eq!(options.dump_transactions, Some("t.csv".to_string()));
+
+        let options = Options::parse(args(&["input.csv", "--tee", "copy.csv"])).unwrap();
+        assert_eq!(options.tee, Some("copy.csv".to_string()));
+
+        let options = Options::parse(args(&["--input-glob", "data/*.csv"])).unwrap();
+        assert_eq!(options.input_glob, Some("data/*.csv".to_string()));
+
+        Options::parse(args(&[])).unwrap_err();
+        Options::parse(args(&["input.csv", "--input-glob", "data/*.csv"])).unwrap_err();
+        Options::parse(args(&["input.csv", "--max-rows"])).unwrap_err();
+        Options::parse(args(&["input.csv", "--max-rows", "ten"])).unwrap_err();
+        Options::parse(args(&["input.csv", "--dance"])).unwrap_err();
+        Options::parse(args(&["input.csv", "--format", "xml"])).unwrap_err();
+        Options::parse(args(&["input.csv", "--clients", "1,x"])).unwrap_err();
+        Options::parse(args(&["input.csv", "--shards", "4"])).unwrap_err();
+        Options::parse(args(&["input.csv", "--liquidity-interval", "4"])).unwrap_err();
+
+        let ratio = |value| Options::parse(args(&["input.csv", "--max-rejected-ratio", value]));
+        assert_eq!(ratio("0").unwrap().max_rejected_ratio, Some(0.0));
+        assert_eq!(ratio("1").unwrap().max_rejected_ratio, Some(1.0));
+        for value in ["-0.1", "1.5", "NaN", "inf"] {
+            let error = ratio(value).unwrap_err();
+            assert!(error.to_string().contains("'--max-rejected-ratio'"));
+        }
+        Options::parse(args(&[
+            "input.csv",
+            "--shards",
+            "0",
+            "--output-prefix",
+            "out",
+        ]))
+        .unwrap_err();
+    }
+
+    #[test]
+    fn max_rows_exceeded() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+deposit, 1, 3, 3.0
+"#;
+        let options = Options {
+            max_rows: Some(3),
+            ..Options::default()
+        };
+        let mut destination = vec![];
+        process_transactions([&source[..]], &mut destination, &options).unwrap();
+        assert!(!destination.is_empty());
+
+        let options = Options {
+            max_rows: Some(2),
+            ..Options::default()
+        };
+        let mut destination = vec![];
+        process_transactions([&source[..]], &mut destination, &options).unwrap_err();
+        assert!(destination.is_empty());
+    }
+
+    #[test]
+    fn rejected_ratio_threshold() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 1.0
+deposit, 1, 3, 1.0
+deposit, 1, 3, 1.0
+withdrawal, 1, 4, 1.0
+withdrawal, 1, 5, 10.0
+deposit, 2, 6, 1.0
+dance, 2, 7, 1.0
+deposit, 2, 8, 1.0
+withdrawal, 2, 9, 1.0
+"#;
+        let handler = handle_input([&source[..]], &Options::default()).unwrap();
+        assert_eq!(handler.statistics().rejected, 3);
+
+        let options = Options {
+            max_rejected_ratio: Some(0.05),
+            ..Options::default()
+        };
+        assert!(check_rejected_ratio(handler.statistics(), &options).unwrap());
+
+        let options = Options {
+            max_rejected_ratio: Some(0.3),
+            ..Options::default()
+        };
+        assert!(!check_rejected_ratio(handler.statistics(), &options).unwrap());
+
+        let options = Options {
+            max_rejected_ratio: Some(0.05),
+            fail_on_rejected_ratio: true,
+            ..Options::default()
+        };
+        check_rejected_ratio(handler.statistics(), &options).unwrap_err();
+    }
+
+    #[cfg(not(feature = "float-amount"))] // compares the exact decimal output
+    #[test]
+    fn sharded_output() {
+        let source = br#"
+type, client, tx, amount
+deposit, 5, 1, 5.0
+deposit, 1, 2, 1.0
+deposit, 2, 3, 2.0
+deposit, 4, 4, 4.0
+"#;
+        let directory =
+            std::env::temp_dir().join(format!("rust-coding-test-shards-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let prefix = directory.join("out");
+        let prefix = prefix.to_str().unwrap();
+
+        let mut handler = handle_input([&source[..]], &Options::default()).unwrap();
+        write_shards(handler.into_iter(), 3, prefix, &WriterConfig::default()).unwrap();
+
+        let read_shard = |shard| std::fs::read_to_string(format!("{}-{}.csv", prefix, shard));
+        let shards: Vec<_> = (0..3).map(|shard| read_shard(shard).unwrap()).collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(shards[0], "");
+        assert_eq!(
+            shards[1],
+            r#"client,available,held,total,locked
+1,1.0,0,1.0,false
+4,4.0,0,4.0,false
+"#
+        );
+        assert_eq!(
+            shards[2],
+            r#"client,available,held,total,locked
+2,2.0,0,2.0,false
+5,5.0,0,5.0,false
+"#
+        );
+    }
+
+    #[cfg(not(feature = "float-amount"))] // compares the exact decimal output
+    #[test]
+    fn reproducible_shards() {
+        let account = |client| Account {
+            client,
+            available: amount("1.5"),
+            held: amount("0"),
+            locked: false,
+        };
+
+        // The store hands out accounts in an arbitrary order, the written shards must not depend on it
+        let ascending = (1..=5).map(account);
+        let shuffled = vec![4, 1, 5, 3, 2].into_iter().map(account);
+        for accounts in [ascending.collect::<Vec<_>>(), shuffled.collect()] {
+            let written: Vec<_> = shard_accounts(accounts.into_iter(), 2)
+                .into_iter()
+                .map(|shard| {
+                    let mut buffer = vec![];
+                    write_accounts_with_config(
+                        &mut buffer,
+                        shard.into_iter(),
+                        &WriterConfig::default(),
+                    )
+                    .unwrap();
+                    String::from_utf8(buffer).unwrap()
+                })
+                .collect();
+            assert_eq!(
+                written,
+                [
+                    "client,available,held,total,locked\n\
+                     2,1.5,0,1.5,false\n\
+                     4,1.5,0,1.5,false\n",
+                    "client,available,held,total,locked\n\
+                     1,1.5,0,1.5,false\n\
+                     3,1.5,0,1.5,false\n\
+                     5,1.5,0,1.5,false\n",
+                ]
+            );
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_compressed_input() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+withdrawal, 1, 3, 0.5
+"#;
+        let compressed = zstd::encode_all(&source[..], 0).unwrap();
+
+        let mut expected = vec![];
+        let plain = decode_input("input.csv", std::io::Cursor::new(source.to_vec())).unwrap();
+        process_transactions([plain], &mut expected, &Options::default()).unwrap();
+
+        let mut result = vec![];
+        let decoded = decode_input("input.csv.zst", std::io::Cursor::new(compressed)).unwrap();
+        process_transactions([decoded], &mut result, &Options::default()).unwrap();
+
+        let mut expected_lines: Vec<_> = expected.split(|c| *c == b'\n').collect();
+        let mut result_lines: Vec<_> = result.split(|c| *c == b'\n').collect();
+        expected_lines.sort();
+        result_lines.sort();
+        assert_eq!(result_lines, expected_lines);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn memory_mapped_input() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+withdrawal, 1, 3, 0.5
+"#;
+        let path =
+            std::env::temp_dir().join(format!("rust-coding-test-mmap-{}.csv", std::process::id()));
+        std::fs::write(&path, &source[..]).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut expected = vec![];
+        let file = std::fs::File::open(path).unwrap();
+        process_transactions([file], &mut expected, &Options::default()).unwrap();
+
+        let mut result = vec![];
+        let mapped = open_input(path).unwrap();
+        process_transactions([mapped], &mut result, &Options::default()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let mut expected_lines: Vec<_> = expected.split(|c| *c == b'\n').collect();
+        let mut result_lines: Vec<_> = result.split(|c| *c == b'\n').collect();
+        expected_lines.sort();
+        result_lines.sort();
+        assert_eq!(result_lines, expected_lines);
+    }
+
+    #[cfg(not(feature = "float-amount"))] // compares the exact decimal output
+    #[test]
+    fn client_filter() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 5, 3, 5.0
+"#;
+        let options = Options {
+            clients: Some(vec![5, 1, 7]),
+            ..Options::default()
+        };
+        let mut destination = vec![];
+        process_transactions([&source[..]], &mut destination, &options).unwrap();
+
+        let output = String::from_utf8(destination).unwrap();
+        let mut lines: Vec<_> = output.lines().skip(1).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["1,1.0,0,1.0,false", "5,5.0,0,5.0,false"]);
+    }
+
+    #[cfg(feature = "glob")]
+    #[test]
+    fn input_glob() {
+        let directory =
+            std::env::temp_dir().join(format!("rust-coding-test-glob-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let write = |name, content: &str| std::fs::write(directory.join(name), content).unwrap();
+        // the dispute only finds its deposit if "a.csv" is processed first
+        write("b.csv", "type,client,tx,amount\ndispute,1,1,\n");
+        write("a.csv", "type,client,tx,amount\ndeposit,1,1,1.0\n");
+        write("c.txt", "type,client,tx,amount\ndeposit,1,2,5.0\n");
+
+        let pattern = directory.join("*.csv");
+        let paths = expand_glob(pattern.to_str().unwrap()).unwrap();
+        let sources: Vec<_> = paths.iter().map(|path| open_input(path).unwrap()).collect();
+        let mut handler = handle_input(sources, &Options::default()).unwrap();
+        expand_glob(directory.join("*.json").to_str().unwrap()).unwrap_err();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with("a.csv"));
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].available, amount("0"));
+        assert_eq!(accounts[0].held, amount("1"));
+    }
+
+    #[cfg(not(feature = "string-tx-id"))]
+    #[test]
+    fn error_summary() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 5.0
+teleport, 1, 3, 1.0
+dispute, 1, 1,
+"#;
+        let path = std::env::temp_dir().join(format!(
+            "rust-coding-test-errors-{}.json",
+            std::process::id()
+        ));
+        let options = Options {
+            error_summary: Some(path.to_str().unwrap().to_string()),
+            ..Options::default()
+        };
+
+        let mut destination = vec![];
+        process_transactions([&source[..]], &mut destination, &options).unwrap();
+        let summary = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let summary: serde_json::Value = serde_json::from_str(&summary).unwrap();
+        assert_eq!(summary["applied"], 2);
+        assert_eq!(summary["rejected"], 2);
+        let rejections = summary["rejections"].as_array().unwrap();
+        assert_eq!(rejections.len(), 2);
+        assert_eq!(rejections[0]["position"], 2);
+        assert_eq!(rejections[0]["type"], "withdrawal");
+        assert_eq!(rejections[0]["client"], 1);
+        assert_eq!(rejections[0]["tx"], 2);
+        assert_eq!(rejections[1]["type"], serde_json::Value::Null);
+        assert!(rejections[1]["error"].is_string());
+    }
+
+    #[test]
+    fn round_trip_output() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+dispute, 2, 2,
+"#;
+        let mut destination = vec![];
+        process_transactions([&source[..]], &mut destination, &Options::default()).unwrap();
+
+        // the accounts are written in an unspecified order
+        let mut accounts = read_accounts(&destination[..]).unwrap();
+        accounts.sort_by_key(|account| account.client);
+        assert_eq!(
+            accounts,
+            vec![
+                Account {
+                    client: 1,
+                    available: amount("1.5"),
+                    held: amount("0"),
+                    locked: false,
+                },
+                Account {
+                    client: 2,
+                    available: amount("0"),
+                    held: amount("2"),
+                    locked: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn tee_output() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+"#;
+        let (mut first, mut second) = (vec![], vec![]);
+        let mut tee = Tee(vec![&mut first, &mut second]);
+        process_transactions([&source[..]], &mut tee, &Options::default()).unwrap();
+
+        assert!(!first.is_empty());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn client_order() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+"#;
+        handle_input([&source[..]], &Options::default()).unwrap();
+
+        let options = Options {
+            client_order: Some(ClientOrder::Grouped),
+            ..Options::default()
+        };
+        let error = handle_input([&source[..]], &options).err().unwrap();
+        assert!(format!("{}", error).contains("client 1 follows client 2"));
+
+        // the violation is reported even if further rows would exceed the maximum
+        let source = [&source[..], b"deposit, 3, 4, 1.0\n"].concat();
+        let options = Options {
+            client_order: Some(ClientOrder::Grouped),
+            max_rows: Some(3),
+            ..Options::default()
+        };
+        let error = handle_input([&source[..]], &options).err().unwrap();
+        assert!(format!("{}", error).contains("client 1 follows client 2"));
+    }
+
+    #[test]
+    fn seed_accounts() {
+        let seed = "client,available,held,total,locked\n1,5,0,5,true\n2,1.5,0.5,2.0,false\n";
+        let path =
+            std::env::temp_dir().join(format!("rust-coding-test-seed-{}.csv", std::process::id()));
+        std::fs::write(&path, seed).unwrap();
+        let options = Options {
+            seed_accounts: Some(path.to_str().unwrap().to_string()),
+            ..Options::default()
+        };
+
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+"#;
+        let handler = handle_input([&source[..]], &options);
+        std::fs::remove_file(&path).unwrap();
+        let mut handler = handler.unwrap();
+
+        assert_eq!(handler.statistics().rejected, 1);
+        let mut accounts: Vec<_> = handler.into_iter().collect();
+        accounts.sort_by_key(|account| account.client);
+        assert_eq!(accounts[0].available.to_string(), "5");
+        assert!(accounts[0].locked);
+        assert_eq!(accounts[1].available.to_string(), "3.5");
+        assert_eq!(accounts[1].held.to_string(), "0.5");
+
+        let options = Options {
+            seed_accounts: Some("does-not-exist.csv".to_string()),
+            ..Options::default()
+        };
+        let error = handle_input([&source[..]], &options).err().unwrap();
+        assert!(format!("{:#}", error).contains("Cannot open seed file 'does-not-exist.csv'"));
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn stable_checksum() {
+        let source: String = (1..=50)
+            .map(|client| format!("deposit, {}, {}, 1.5\n", client, client))
+            .collect();
+        let source = format!("type, client, tx, amount\n{}", source);
+        let path = std::env::temp_dir().join(format!(
+            "rust-coding-test-checksum-{}.txt",
+            std::process::id()
+        ));
+        let options = Options {
+            checksum: Some(path.to_str().unwrap().to_string()),
+            ..Options::default()
+        };
+
+        let mut checksums = vec![];
+        for _ in 0..2 {
+            let mut output = vec![];
+            process_transactions([source.as_bytes()], &mut output, &options).unwrap();
+            checksums.push(std::fs::read_to_string(&path).unwrap());
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(checksums[0].trim().len(), 64);
+        assert_eq!(checksums[0], checksums[1]);
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(is_empty_input(&mut &b""[..]));
+        assert!(!is_empty_input(&mut &b"type, client, tx, amount\n"[..]));
+
+        let path =
+            std::env::temp_dir().join(format!("rust-coding-test-empty-{}.csv", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        let path = path.to_str().unwrap();
+
+        let open = |options: &Options| {
+            capture_logs(log::Level::Warn, || {
+                let source = open_checked_input(path, options).unwrap();
+                let mut output = vec![];
+                process_transactions([source], &mut output, options).unwrap();
+                assert!(output.is_empty());
+            })
+        };
+        assert!(open(&Options::default()).is_empty());
+
+        let warnings = open(&Options {
+            warn_empty_input: true,
+            ..Options::default()
+        });
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            warnings,
+            vec![format!(
+                "Input '{}' is empty (0 bytes), this often indicates an upstream failure",
+                path
+            )]
+        );
+
+        let error = open_input("does-not-exist.csv").err().unwrap();
+        assert!(format!("{:#}", error).contains("Cannot open input file 'does-not-exist.csv'"));
+    }
+
+    #[test]
+    fn disputed_column() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+deposit, 1, 3, 3.0
+deposit, 2, 4, 4.0
+dispute, 1, 1,
+dispute, 1, 2,
+dispute, 1, 3,
+resolve, 1, 3,
+"#;
+        let options = Options {
+            disputed_column: true,
+            clients: Some(vec![1]),
+            ..Options::default()
+        };
+        let mut output = vec![];
+        process_transactions([&source[..]], &mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,disputed\n1,3.0,3.0,6.0,false,2\n"
+        );
+    }
 }
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+
+use crate::types::{ClientId, Transaction, TransactionId, TransactionType};
+
+/// A "dispute", "resolve", or "chargeback" that references no earlier disputable transaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferenceIssue {
+    /// Index of the offending transaction in the stream (0-based)
+    pub position: usize,
+    pub transaction_type: TransactionType,
+    pub client: ClientId,
+    pub transaction: TransactionId,
+}
+
+/// Check (without processing them) that all dispute-related transactions reference a deposit that
+/// appears earlier in the stream (or a withdrawal, matching `HandlerConfig::disputable_withdrawals`)
+///
+/// Only the existence of the transaction is checked, the transactions can still fail for other
+/// reasons (e.g. a client mismatch or a missing dispute). All disputable IDs are kept in memory.
+pub fn validate_references(
+    transactions: impl IntoIterator<Item = Transaction>,
+    disputable_withdrawals: bool,
+) -> Vec<ReferenceIssue> {
+    let mut disputable = HashSet::new();
+    let mut issues = vec![];
+
+    for (position, transaction) in transactions.into_iter().enumerate() {
+        let transaction_type = transaction.transaction_type();
+        match transaction {
+            Transaction::Deposit(record) => {
+                disputable.insert(record.transaction);
+            }
+            Transaction::Withdrawal(record) => {
+                if disputable_withdrawals {
+                    disputable.insert(record.transaction);
+                }
+            }
+            Transaction::Dispute(record)
+            | Transaction::Resolve(record)
+            | Transaction::Chargeback(record) => {
+                if !disputable.contains(&record.transaction) {
+                    issues.push(ReferenceIssue {
+                        position,
+                        transaction_type,
+                        client: record.client,
+                        transaction: record.transaction,
+                    });
+                }
+            }
+        }
+    }
+    issues
+}
+
+/// Required order of the clients in the input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientOrder {
+    /// All transactions of a client are adjacent (in any order of the clients)
+    Grouped,
+
+    /// All transactions of a client are adjacent and the clients are in ascending order
+    Sorted,
+}
+
+impl std::str::FromStr for ClientOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(order: &str) -> Result<Self> {
+        match order {
+            "grouped" => Ok(ClientOrder::Grouped),
+            "sorted" => Ok(ClientOrder::Sorted),
+            _ => Err(anyhow!("Unknown client order '{}'", order)),
+        }
+    }
+}
+
+/// Streaming check that the clients of the transactions are in the required order
+/// Only the last client (and all finished clients for `ClientOrder::Grouped`) are kept.
+#[derive(Debug, Clone)]
+pub struct ClientOrderCheck {
+    order: ClientOrder,
+    last: Option<ClientId>,
+    finished: HashSet<ClientId>,
+}
+
+impl ClientOrderCheck {
+    pub fn new(order: ClientOrder) -> Self {
+        Self {
+            order,
+            last: None,
+            finished: HashSet::new(),
+        }
+    }
+
+    /// Check the client of the next transaction (at `position`, 0-based)
+    pub fn check(&mut self, position: usize, client: ClientId) -> Result<()> {
+        let last = match self.last.replace(client) {
+            Some(last) if last != client => last,
+            _ => return Ok(()),
+        };
+
+        let out_of_order = match self.order {
+            ClientOrder::Grouped => {
+                self.finished.insert(last);
+                self.finished.contains(&client)
+            }
+            ClientOrder::Sorted => client < last,
+        };
+        if out_of_order {
+            let order = match self.order {
+                ClientOrder::Grouped => "grouped",
+                ClientOrder::Sorted => "sorted",
+            };
+            return Err(anyhow!(
+                "Input is not {} by client, client {} follows client {} (position {})",
+                order,
+                client,
+                last,
+                position
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+    use crate::test_support::tx;
+    use crate::types::*;
+
+    #[test]
+    fn unknown_references() {
+        let transactions = vec![
+            Transaction::Dispute(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(1.0),
+            }),
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+                amount: amount!(1.0),
+            }),
+            Transaction::Dispute(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }),
+            Transaction::Chargeback(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+            }),
+            Transaction::Resolve(DisputedTransactionRecord {
+                client: 3,
+                transaction: tx(99),
+            }),
+        ];
+
+        assert_eq!(
+            validate_references(transactions.clone(), false),
+            vec![
+                ReferenceIssue {
+                    position: 0,
+                    transaction_type: TransactionType::Dispute,
+                    client: 0,
+                    transaction: tx(1),
+                },
+                ReferenceIssue {
+                    position: 4,
+                    transaction_type: TransactionType::Chargeback,
+                    client: 0,
+                    transaction: tx(2),
+                },
+                ReferenceIssue {
+                    position: 5,
+                    transaction_type: TransactionType::Resolve,
+                    client: 3,
+                    transaction: tx(99),
+                },
+            ]
+        );
+
+        // the chargeback of the withdrawal is only valid if withdrawals are disputable
+        assert_eq!(
+            validate_references(transactions, true),
+            vec![
+                ReferenceIssue {
+                    position: 0,
+                    transaction_type: TransactionType::Dispute,
+                    client: 0,
+                    transaction: tx(1),
+                },
+                ReferenceIssue {
+                    position: 5,
+                    transaction_type: TransactionType::Resolve,
+                    client: 3,
+                    transaction: tx(99),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn client_order() {
+        let check = |order, clients: &[ClientId]| {
+            let mut check = ClientOrderCheck::new(order);
+            clients
+                .iter()
+                .enumerate()
+                .try_for_each(|(position, client)| check.check(position, *client))
+        };
+
+        check(ClientOrder::Grouped, &[3, 3, 1, 2, 2]).unwrap();
+        check(ClientOrder::Sorted, &[1, 1, 2, 3, 3]).unwrap();
+
+        let error = check(ClientOrder::Grouped, &[1, 2, 1]).unwrap_err();
+        assert!(format!("{}", error).contains("position 2"));
+        check(ClientOrder::Sorted, &[3, 3, 1, 2, 2]).unwrap_err();
+    }
+}
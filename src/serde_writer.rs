@@ -0,0 +1,83 @@
+use anyhow::Result;
+use serde::Serializer;
+
+use crate::types::Account;
+
+/// Serialize all accounts as a sequence with any serde `Serializer` (e.g. for TOML or MessagePack)
+///
+/// Every account is serialized as a struct with the same fields as the CSV output, including the
+/// computed `total`.
+pub fn write_accounts_with<S: Serializer>(
+    serializer: S,
+    accounts: impl Iterator<Item = Account>,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(accounts)
+}
+
+/// Write all accounts to the provided destination (as a MessagePack array of arrays)
+#[cfg(feature = "msgpack")]
+pub fn write_accounts_msgpack(
+    destination: &mut dyn std::io::Write,
+    accounts: impl Iterator<Item = Account>,
+) -> Result<()> {
+    let mut serializer = rmp_serde::Serializer::new(destination);
+    write_accounts_with(&mut serializer, accounts)?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "msgpack"))]
+mod tests {
+    use super::*;
+    use crate::test_support::amount;
+    use crate::types::{Amount, ClientId};
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct AccountRow {
+        client: ClientId,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+        locked: bool,
+    }
+
+    #[test]
+    fn msgpack_round_trip() {
+        let accounts = vec![
+            Account {
+                client: 1,
+                available: amount!(1.5),
+                held: amount!(0.25),
+                locked: false,
+            },
+            Account {
+                client: 2,
+                available: amount!(0),
+                held: amount!(0),
+                locked: true,
+            },
+        ];
+
+        let mut buffer = vec![];
+        write_accounts_msgpack(&mut buffer, accounts.into_iter()).unwrap();
+        let rows: Vec<AccountRow> = rmp_serde::from_slice(&buffer).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                AccountRow {
+                    client: 1,
+                    available: amount!(1.5),
+                    held: amount!(0.25),
+                    total: amount!(1.75),
+                    locked: false,
+                },
+                AccountRow {
+                    client: 2,
+                    available: amount!(0),
+                    held: amount!(0),
+                    total: amount!(0),
+                    locked: true,
+                },
+            ]
+        );
+    }
+}
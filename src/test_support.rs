@@ -0,0 +1,36 @@
+//! Helpers that keep the tests independent of the configured ID type
+
+use crate::types::TransactionId;
+
+mod logger;
+pub use logger::capture_logs;
+
+/// The transaction ID for `id` (its decimal text with the `string-tx-id` feature)
+#[cfg(not(feature = "string-tx-id"))]
+pub fn tx(id: u32) -> TransactionId {
+    id
+}
+
+/// The transaction ID for `id` (its decimal text with the `string-tx-id` feature)
+#[cfg(feature = "string-tx-id")]
+pub fn tx(id: u32) -> TransactionId {
+    id.to_string().parse().unwrap()
+}
+
+/// The amount for a decimal literal (exact by default, the nearest `f64` with `float-amount`)
+#[cfg(not(feature = "float-amount"))]
+macro_rules! amount {
+    ($($value:tt)+) => {
+        rust_decimal_macros::dec!($($value)+)
+    };
+}
+
+/// The amount for a decimal literal (exact by default, the nearest `f64` with `float-amount`)
+#[cfg(feature = "float-amount")]
+macro_rules! amount {
+    ($value:expr) => {
+        ($value) as f64
+    };
+}
+
+pub(crate) use amount;
@@ -0,0 +1,50 @@
+//! A logger for tests that only captures the messages of the thread asking for them
+//!
+//! It is shared with the tests of the binary (which cannot see the library's test helpers), so it
+//! only relies on the `log` crate.
+
+use std::cell::RefCell;
+
+thread_local! {
+    /// The level being captured on this thread and the messages so far, `None` while not capturing
+    static CAPTURED: RefCell<Option<(log::Level, Vec<String>)>> = const { RefCell::new(None) };
+}
+
+/// Forwards messages to the capturing thread, so that concurrently running tests do not interfere
+struct CapturingLogger;
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        CAPTURED.with(|captured| {
+            matches!(&*captured.borrow(), Some((level, _)) if metadata.level() == *level)
+        })
+    }
+
+    fn log(&self, record: &log::Record) {
+        CAPTURED.with(|captured| {
+            if let Some((level, messages)) = &mut *captured.borrow_mut() {
+                if record.level() == *level {
+                    messages.push(record.args().to_string());
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Run `f` and return the messages it logged on this thread at exactly `level`
+pub fn capture_logs(level: log::Level, f: impl FnOnce()) -> Vec<String> {
+    static LOGGER: CapturingLogger = CapturingLogger;
+    let _ = log::set_logger(&LOGGER); // only the first call installs the logger
+    if level.to_level_filter() > log::max_level() {
+        log::set_max_level(level.to_level_filter()); // never lowered for other running tests
+    }
+
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some((level, vec![])));
+    f();
+    CAPTURED
+        .with(|captured| captured.borrow_mut().take())
+        .map(|(_, messages)| messages)
+        .unwrap_or_default()
+}
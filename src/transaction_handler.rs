@@ -1,21 +1,249 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
+use crate::error::TransactionError;
 use crate::types::{
-    Account, DisputableTransaction, DisputedTransactionRecord, MonetaryTransactionRecord,
-    Transaction,
+    Account, Amount, ClientId, DisputableTransaction, DisputeOutcome, DisputeStatus,
+    DisputedTransactionRecord, MonetaryTransactionRecord, StoredTransaction, SubMinorUnitPolicy,
+    Transaction, TransactionId, TransactionType, ValidationConfig,
 };
 use crate::{
-    account_store::{AccountStore, HashMapAccountStore},
-    transaction_store::{HashMapTransactionStore, TransactionStore, UndisputeOutcome},
+    account_store::{AccountSnapshot, AccountStore, Granularity, HashMapAccountStore},
+    transaction_store::{
+        HashMapTransactionStore, TransactionSnapshot, TransactionStore, UndisputeOutcome,
+    },
 };
 
+/// Sums of all successfully applied money movements
+#[derive(Debug, Clone, Default, PartialEq)]
+struct MoneyFlows {
+    /// Total funds of the accounts given by `with_opening_balances`
+    opening: Amount,
+    deposits: Amount,
+    withdrawals: Amount,
+    charged_back: Amount,
+
+    /// Currently held funds (not a flow, but kept up to date the same way)
+    held: Amount,
+}
+
+/// Everything an applied transaction may have changed, captured before it was applied
+#[derive(Clone)]
+struct UndoEntry {
+    client: ClientId,
+    account: AccountSnapshot,
+    transaction: TransactionId,
+    stored: TransactionSnapshot,
+    flows: MoneyFlows,
+    disputes: Option<usize>,
+}
+
+/// Compares the money that should be in the system with what the accounts actually hold
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reconciliation {
+    /// Total funds of the opening balances (0 unless the handler was seeded with them)
+    pub opening_total: Amount,
+    pub total_deposits: Amount,
+    pub total_withdrawals: Amount,
+    pub total_held: Amount,
+    pub total_charged_back: Amount,
+
+    /// Opening total plus deposits minus withdrawals minus chargebacks
+    pub computed_total: Amount,
+
+    /// Sum of the total funds of all accounts
+    pub observed_total: Amount,
+}
+
+/// A client whose account differs between two handlers (see `TransactionHandler::diff`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountDiff {
+    pub client: ClientId,
+
+    /// The account in the handler that `diff` was called on (`None` if it does not exist there)
+    pub own: Option<Account>,
+
+    /// The account in the other handler (`None` if it does not exist there)
+    pub other: Option<Account>,
+}
+
+/// Liquidity of the whole system after a number of processed transactions
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiquiditySample {
+    /// Transactions seen by `handle_transactions` so far (applied or rejected)
+    pub processed: usize,
+    pub total_available: Amount,
+    pub total_held: Amount,
+}
+
+/// Counts of the transactions seen by `handle_transactions`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Statistics {
+    pub applied: usize,
+    pub rejected: usize,
+}
+
+impl Statistics {
+    /// Fraction of rejected transactions (including unparsable rows), 0 if there were none at all
+    pub fn rejected_ratio(&self) -> f64 {
+        let total = self.applied + self.rejected;
+        if total == 0 {
+            0.0
+        } else {
+            self.rejected as f64 / total as f64
+        }
+    }
+}
+
+/// A transaction that `handle_transactions` did not apply
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Rejection {
+    /// Index of the transaction in processing order (1-based, unparsable rows included)
+    pub position: usize,
+
+    /// Type, client, and ID of the transaction, `None` if it could not be parsed at all
+    #[serde(rename = "type")]
+    pub transaction_type: Option<TransactionType>,
+    pub client: Option<ClientId>,
+    #[serde(rename = "tx")]
+    pub transaction: Option<TransactionId>,
+
+    pub error: String,
+}
+
+/// How a dispute of a deposit above `DisputeCap::max_amount` is handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisputeCapMode {
+    /// Refuse the dispute with `TransactionError::DisputeAmountExceeded`
+    Reject,
+
+    /// Accept the dispute but only hold up to the maximum amount
+    Hold,
+}
+
+/// Upper limit for the amount a single dispute can hold
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisputeCap {
+    pub max_amount: Amount,
+    pub mode: DisputeCapMode,
+}
+
+/// Options to adjust the business logic, the default is the standard behavior described in README.md
+#[derive(Debug, Clone, Default)]
+pub struct HandlerConfig {
+    /// Keep at most this many transactions for later disputes to bound the memory usage
+    /// Deposits beyond the limit still change the balance but cannot be disputed. Their IDs are not
+    /// checked for duplicates either.
+    pub max_stored_transactions: Option<usize>,
+
+    /// Rules that the amounts of deposits and withdrawals must follow
+    pub validation: ValidationConfig,
+
+    /// Only release held funds in multiples of this unit (unconstrained if `None`)
+    pub release_granularity: Option<Granularity>,
+
+    /// Reject all transactions of these clients (e.g. a sentinel ID like 0)
+    pub reserved_clients: HashSet<ClientId>,
+
+    /// Record a `LiquiditySample` every this many transactions in `handle_transactions`
+    pub liquidity_sample_interval: Option<usize>,
+
+    /// Reject all transactions of other types (all types are allowed if `None`)
+    pub allowed_types: Option<HashSet<TransactionType>>,
+
+    /// Remember the clients of rejected deposits and withdrawals for `phantom_clients`
+    pub track_phantom_clients: bool,
+
+    /// Refuse disputes, resolves, and chargebacks for locked accounts (see README.md)
+    pub strict_freeze: bool,
+
+    /// Do not lock the account for a chargeback that finds no held funds to take
+    pub skip_lock_on_empty_chargeback: bool,
+
+    /// Store withdrawals so that they can be disputed, resolved, and charged back like deposits
+    /// (with the opposite movement of funds, see README.md)
+    pub disputable_withdrawals: bool,
+
+    /// Never lock the account for a chargeback, which then only takes the held funds (e.g. to
+    /// finalize a refund in a chargeback-reversal workflow)
+    pub skip_lock_on_chargeback: bool,
+
+    /// Reject deposits and withdrawals reusing the ID of an earlier one of the other kind
+    /// Duplicate deposit IDs are always rejected. Withdrawal IDs are only tracked once applied.
+    pub unique_transaction_ids: bool,
+
+    /// Refuse new disputes for accounts locked by a chargeback with
+    /// `TransactionError::FrozenByChargeback` (or `TransactionError::AccountFrozen` if the account
+    /// was already locked in its opening balance)
+    pub freeze_after_chargeback: bool,
+
+    /// Keep every rejected transaction with its error (see `TransactionHandler::rejections`)
+    pub collect_rejections: bool,
+
+    /// Commit the transaction store every this many transactions (and at the end of the input)
+    /// instead of relying on the store to persist every change on its own
+    pub commit_interval: Option<usize>,
+
+    /// Reject further disputes of a client that has opened this many disputes in this run
+    pub max_disputes_per_client: Option<usize>,
+
+    /// Accept a "resolve" for an already resolved transaction as a (logged) no-op
+    pub idempotent_resolve: bool,
+
+    /// Limit the amount of a single dispute (unlimited if `None`)
+    pub dispute_cap: Option<DisputeCap>,
+
+    /// Round, truncate, or reject fractions of a minor unit in the amounts of deposits and
+    /// withdrawals, held funds are rounded the same way
+    pub sub_minor_units: SubMinorUnitPolicy,
+
+    /// Apply all deposits and withdrawals before all disputes, resolves, and chargebacks (each in
+    /// input order), so that disputes can reference deposits further down the input
+    /// All transactions are buffered in memory for this.
+    pub two_pass: bool,
+
+    /// Keep the information to undo up to this many of the latest applied transactions
+    pub undo_depth: usize,
+}
+
 /// Can process a series of transactions while keeping track of the system's state
-pub struct TransactionHandler {
+/// Cloning creates an independent snapshot of the whole state, e.g. for what-if analysis.
+#[derive(Clone)]
+pub struct TransactionHandler<S = HashMapTransactionStore> {
     account_store: HashMapAccountStore,
-    transaction_store: HashMapTransactionStore,
+    transaction_store: S,
+    flows: MoneyFlows,
+    statistics: Statistics,
+    validation: ValidationConfig,
+    reserved_clients: HashSet<ClientId>,
+    liquidity_sample_interval: Option<usize>,
+    liquidity_series: Vec<LiquiditySample>,
+    allowed_types: Option<HashSet<TransactionType>>,
+
+    /// Clients of rejected deposits and withdrawals (only if tracking is enabled)
+    rejected_clients: Option<HashSet<ClientId>>,
+    strict_freeze: bool,
+    skip_lock_on_empty_chargeback: bool,
+    disputable_withdrawals: bool,
+    freeze_after_chargeback: bool,
+    unique_transaction_ids: bool,
+    two_pass: bool,
+    idempotent_resolve: bool,
+    max_disputes_per_client: Option<usize>,
+    dispute_cap: Option<DisputeCap>,
+    sub_minor_units: SubMinorUnitPolicy,
+
+    /// Number of disputes opened by each client (only if they are limited)
+    dispute_counts: HashMap<ClientId, usize>,
+    commit_interval: Option<usize>,
+
+    /// Rejected transactions (only if collecting them is enabled)
+    rejections: Option<Vec<Rejection>>,
+    undo_depth: usize,
+    undo_stack: VecDeque<UndoEntry>,
 }
 
-impl<'a> IntoIterator for &'a mut TransactionHandler {
+impl<'a, S> IntoIterator for &'a mut TransactionHandler<S> {
     type Item = Account;
 
     type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
@@ -27,15 +255,98 @@ impl<'a> IntoIterator for &'a mut TransactionHandler {
 
 impl TransactionHandler {
     pub fn new() -> Self {
+        Self::with_config(HandlerConfig::default())
+    }
+
+    pub fn with_config(config: HandlerConfig) -> Self {
+        let transaction_store = match config.max_stored_transactions {
+            Some(limit) => HashMapTransactionStore::with_limit(limit),
+            None => HashMapTransactionStore::new(),
+        };
+        Self::with_store(config, transaction_store)
+    }
+
+    /// Create a handler whose accounts start with the given balances (e.g. yesterday's output)
+    ///
+    /// Only the balances are known, not the transactions that lead to them. Those transactions
+    /// can therefore not be disputed and any funds held in the opening balances stay held.
+    pub fn with_opening_balances(accounts: impl IntoIterator<Item = Account>) -> Result<Self> {
+        let mut handler = Self::new();
+        handler.open_accounts(accounts)?;
+        Ok(handler)
+    }
+}
+
+impl<S: TransactionStore> TransactionHandler<S> {
+    /// Create a handler that keeps the transactions in the given store (e.g. a persistent one)
+    /// `HandlerConfig::max_stored_transactions` is ignored, the store has to be limited itself.
+    pub fn with_store(config: HandlerConfig, transaction_store: S) -> Self {
+        let mut account_store = match config.release_granularity {
+            Some(granularity) => HashMapAccountStore::with_release_granularity(granularity),
+            None => HashMapAccountStore::new(),
+        };
+        account_store.set_hold_rounding(config.sub_minor_units);
+        account_store.set_lock_on_chargeback(!config.skip_lock_on_chargeback);
+
         Self {
-            account_store: HashMapAccountStore::new(),
-            transaction_store: HashMapTransactionStore::new(),
+            account_store,
+            transaction_store,
+            flows: MoneyFlows::default(),
+            statistics: Statistics::default(),
+            validation: config.validation,
+            reserved_clients: config.reserved_clients,
+            liquidity_sample_interval: config.liquidity_sample_interval,
+            liquidity_series: Vec::new(),
+            allowed_types: config.allowed_types,
+            rejected_clients: if config.track_phantom_clients {
+                Some(HashSet::new())
+            } else {
+                None
+            },
+            strict_freeze: config.strict_freeze,
+            skip_lock_on_empty_chargeback: config.skip_lock_on_empty_chargeback,
+            disputable_withdrawals: config.disputable_withdrawals,
+            freeze_after_chargeback: config.freeze_after_chargeback,
+            unique_transaction_ids: config.unique_transaction_ids,
+            two_pass: config.two_pass,
+            idempotent_resolve: config.idempotent_resolve,
+            max_disputes_per_client: config.max_disputes_per_client,
+            dispute_cap: config.dispute_cap,
+            sub_minor_units: config.sub_minor_units,
+            dispute_counts: HashMap::new(),
+            commit_interval: config.commit_interval,
+            rejections: if config.collect_rejections {
+                Some(vec![])
+            } else {
+                None
+            },
+            undo_depth: config.undo_depth,
+            undo_stack: VecDeque::new(),
+        }
+    }
+
+    /// Add accounts with the given opening balances (and locked states) to a configured handler
+    /// The same restrictions as for `with_opening_balances` apply, none of the clients may have an
+    /// account yet.
+    pub fn open_accounts(&mut self, accounts: impl IntoIterator<Item = Account>) -> Result<()> {
+        for account in accounts {
+            self.flows.opening += account.total();
+            self.flows.held += account.held;
+            self.account_store.open_account(account)?;
         }
+        Ok(())
     }
 
     /// Handle a single "deposit" transaction
     /// The client's available funds will go up and the transaction will be stored for later use
     fn handle_deposit(&mut self, record: MonetaryTransactionRecord) -> Result<()> {
+        if self.unique_transaction_ids && self.transaction_store.is_withdrawal(record.transaction) {
+            return Err(TransactionError::DuplicateTransaction {
+                transaction: record.transaction,
+            }
+            .into());
+        }
+
         let transaction_result = self
             .transaction_store
             .add_transaction(DisputableTransaction::Deposit(record.clone()));
@@ -46,48 +357,220 @@ impl TransactionHandler {
         transaction_result.and_then(|_| {
             self.account_store
                 .add_to_balance(record.client, record.amount)
-        })
+        })?;
+
+        self.flows.deposits += record.amount;
+        Ok(())
     }
 
     /// Handle a single "withdrawal" transaction
     /// The client's available funds will go down if they have been sufficient for the transaction,
     /// otherwise the transaction will be ignored.
     fn handle_withdrawal(&mut self, record: MonetaryTransactionRecord) -> Result<()> {
+        let store = &self.transaction_store;
+        let known = store.transaction(record.transaction).is_some()
+            || store.is_withdrawal(record.transaction);
+        if self.unique_transaction_ids && known {
+            return Err(TransactionError::DuplicateTransaction {
+                transaction: record.transaction,
+            }
+            .into());
+        }
+        if self.disputable_withdrawals && store.transaction(record.transaction).is_some() {
+            return Err(anyhow!(
+                "Transaction already present (tx = {})",
+                record.transaction
+            ));
+        }
+
         self.account_store
-            .add_to_balance(record.client, -record.amount)
+            .add_to_balance(record.client, -record.amount)?;
+        if self.disputable_withdrawals {
+            self.transaction_store
+                .add_transaction(DisputableTransaction::Withdrawal(record.clone()))?;
+        } else {
+            self.transaction_store.add_withdrawal(record.transaction);
+        }
+
+        self.flows.withdrawals += record.amount;
+        Ok(())
     }
 
     /// Handle a single "dispute" transaction
     /// If the disputed transaction exists, and belongs to the client, the amount from the
     /// transaction is held back for further handling.
-    /// As only "deposit" transactions are stored, only those can be disputed successfully.
+    /// Only "deposit" transactions (and "withdrawal" transactions if they are disputable) are
+    /// stored, so only those can be disputed successfully. The amount of a disputed withdrawal is
+    /// held on top of the client's funds, as it may have to be returned.
+    /// Disputing a zero-amount transaction would not hold anything, so it is skipped entirely.
+    /// Only transactions applied before the dispute can be found (processing is single-pass).
     fn handle_dispute(&mut self, record: DisputedTransactionRecord) -> Result<()> {
+        if let Some(transaction) = self.transaction_store.transaction(record.transaction) {
+            let data = transaction.record();
+            if data.client == record.client && data.amount == Amount::default() {
+                debug!(
+                    "Skipping dispute of zero-amount transaction (tx = {})",
+                    record.transaction
+                );
+                return Ok(());
+            }
+
+            if let Some(cap) = &self.dispute_cap {
+                if data.client == record.client
+                    && data.amount > cap.max_amount
+                    && cap.mode == DisputeCapMode::Reject
+                {
+                    return Err(TransactionError::DisputeAmountExceeded {
+                        transaction: record.transaction,
+                        max_amount: cap.max_amount,
+                    }
+                    .into());
+                }
+            }
+        }
+
+        if let Some(max_disputes) = self.max_disputes_per_client {
+            if self
+                .dispute_counts
+                .get(&record.client)
+                .copied()
+                .unwrap_or(0)
+                >= max_disputes
+            {
+                return Err(TransactionError::DisputeRateLimited {
+                    client: record.client,
+                    transaction: record.transaction,
+                    max_disputes,
+                }
+                .into());
+            }
+        }
+
         let transaction_result = self.transaction_store.dispute_transaction(&record);
 
         transaction_result.and_then(|transaction| {
-            let DisputableTransaction::Deposit(data) = transaction;
-            self.account_store.hold_amount(data.client, data.amount)
+            let data = transaction.record();
+            let mut amount = data.amount;
+            if let Some(cap) = &self.dispute_cap {
+                if amount > cap.max_amount {
+                    info!(
+                        "Holding only {} of the disputed {} (tx = {})",
+                        cap.max_amount, amount, data.transaction
+                    );
+                    amount = cap.max_amount;
+                }
+            }
+
+            let held = match transaction {
+                DisputableTransaction::Deposit(_) => {
+                    self.account_store
+                        .hold_amount(data.client, data.transaction, amount)?
+                }
+                DisputableTransaction::Withdrawal(_) => {
+                    let held = self.account_store.hold_reversed_amount(
+                        data.client,
+                        data.transaction,
+                        amount,
+                    )?;
+                    // The withdrawal counts as reversed until the dispute is resolved
+                    self.flows.withdrawals -= held;
+                    held
+                }
+            };
+
+            self.flows.held += held;
+            if self.max_disputes_per_client.is_some() {
+                *self.dispute_counts.entry(record.client).or_default() += 1;
+            }
+            Ok(())
         })
     }
 
     /// Handle a single "resolve" transaction
     /// If the referenced transaction exists, belongs to the client, and was disputed, the held back
     /// amount from the transaction is released into the client's available funds.
+    /// Held funds are only ever released to the client who made the original deposit.
+    /// For a disputed withdrawal, the withdrawal stands and the held amount is dropped.
     fn handle_resolve(&mut self, record: DisputedTransactionRecord) -> Result<()> {
+        if let Some(transaction) = self.transaction_store.transaction(record.transaction) {
+            let data = transaction.record();
+            if data.client != record.client {
+                return Err(TransactionError::ResolveClientMismatch {
+                    transaction: record.transaction,
+                    expected: data.client,
+                    found: record.client,
+                }
+                .into());
+            }
+
+            // Fail before the dispute is closed, otherwise its funds would stay held for good
+            if let DisputableTransaction::Deposit(_) = transaction {
+                if let Some(held) = self
+                    .account_store
+                    .held_for(record.client, record.transaction)
+                {
+                    self.account_store.check_release(record.transaction, held)?;
+                }
+            }
+        }
+
         let transaction_result = self
             .transaction_store
             .undispute_transaction(&record, UndisputeOutcome::Resolve);
 
+        if self.idempotent_resolve {
+            if let Err(error) = &transaction_result {
+                if let Some(TransactionError::NotCurrentlyDisputed {
+                    status: DisputeStatus::Resolved,
+                    ..
+                }) = error.downcast_ref()
+                {
+                    info!("Ignoring repeated resolve (tx = {})", record.transaction);
+                    return Ok(());
+                }
+            }
+        }
+
         transaction_result.and_then(|transaction| {
-            let DisputableTransaction::Deposit(data) = transaction;
-            self.account_store
-                .release_held_amount(data.client, data.amount)
+            let data = transaction.record();
+            let released = match transaction {
+                DisputableTransaction::Deposit(_) => self.account_store.release_held_amount(
+                    data.client,
+                    data.transaction,
+                    self.disputed_amount(data),
+                )?,
+                DisputableTransaction::Withdrawal(_) => {
+                    let dropped = self.account_store.discard_reversed_amount(
+                        data.client,
+                        data.transaction,
+                        data.amount,
+                    )?;
+                    self.flows.withdrawals += dropped;
+                    dropped
+                }
+            };
+
+            self.flows.held -= released;
+            Ok(())
         })
     }
 
+    /// The amount to settle when resolving or charging back the dispute of `data`
+    /// This is the transaction amount, unless the dispute held a different amount (due to a
+    /// dispute cap or the rounding of holds).
+    fn disputed_amount(&self, data: &MonetaryTransactionRecord) -> Amount {
+        self.account_store
+            .held_for(data.client, data.transaction)
+            .unwrap_or(data.amount)
+    }
+
     /// Handle a single "chargeback" transaction
     /// If the referenced transaction exists, belongs to the client, and was disputed, the held back
-    /// amount from the transaction removed from the client's account and the account is frozen.
+    /// amount from the transaction removed from the client's account and the account is frozen
+    /// (unless chargebacks are configured not to lock).
+    /// A chargeback without any held funds is logged as an anomaly (and optionally does not freeze).
+    /// For a disputed withdrawal, the held amount becomes available again (the withdrawal is
+    /// reversed) and the account is frozen the same way.
     fn handle_chargeback(&mut self, record: DisputedTransactionRecord) -> Result<()> {
         let transaction_result = self
             .transaction_store
@@ -95,27 +578,415 @@ impl TransactionHandler {
 
         // The following call includes the "freeze"
         transaction_result.and_then(|transaction| {
-            let DisputableTransaction::Deposit(data) = transaction;
-            self.account_store
-                .charge_back_amount(data.client, data.amount)
+            let data = transaction.record();
+            let held = self.account_store.held_for(data.client, data.transaction);
+            if held == Some(Amount::default()) {
+                warn!(
+                    "Chargeback without held funds (client = {}, tx = {})",
+                    data.client, data.transaction
+                );
+                if self.skip_lock_on_empty_chargeback {
+                    // Releasing nothing just clears the (empty) hold of the transaction
+                    self.account_store.release_held_amount(
+                        data.client,
+                        data.transaction,
+                        Amount::default(),
+                    )?;
+                    return Ok(());
+                }
+            }
+
+            match transaction {
+                DisputableTransaction::Deposit(_) => {
+                    let charged_back = self.account_store.charge_back_amount(
+                        data.client,
+                        data.transaction,
+                        self.disputed_amount(data),
+                    )?;
+                    self.flows.charged_back += charged_back;
+                    self.flows.held -= charged_back;
+                }
+                DisputableTransaction::Withdrawal(_) => {
+                    // The withdrawal has already been counted as reversed by the dispute
+                    let reversed = self.account_store.charge_back_reversed_amount(
+                        data.client,
+                        data.transaction,
+                        data.amount,
+                    )?;
+                    self.flows.held -= reversed;
+                }
+            }
+            Ok(())
         })
     }
 
+    /// Handle exactly one transaction
+    /// Unlike `handle_transactions`, a rejected transaction is reported to the caller.
+    pub fn apply(&mut self, transaction: Transaction) -> Result<()> {
+        let client = transaction.client();
+        if self.reserved_clients.contains(&client) {
+            return Err(TransactionError::ReservedClient { client }.into());
+        }
+
+        let transaction_type = transaction.transaction_type();
+        if let Some(allowed_types) = &self.allowed_types {
+            if !allowed_types.contains(&transaction_type) {
+                return Err(TransactionError::TransactionTypeDisabled { transaction_type }.into());
+            }
+        }
+
+        let dispute_activity = matches!(
+            transaction_type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        // accounts are only ever locked by chargebacks (in this or a previous run)
+        if self.freeze_after_chargeback
+            && transaction_type == TransactionType::Dispute
+            && self.account_store.is_locked(client)
+        {
+            let transaction = transaction.transaction_id();
+            return Err(match self.account_store.locked_by(client) {
+                Some(prior_transaction) => TransactionError::FrozenByChargeback {
+                    client,
+                    transaction,
+                    prior_transaction: Box::new(prior_transaction),
+                },
+                None => TransactionError::AccountFrozen {
+                    client,
+                    transaction,
+                },
+            }
+            .into());
+        }
+
+        if self.strict_freeze && dispute_activity && self.account_store.is_locked(client) {
+            return Err(anyhow!(
+                "Cannot {} for locked account (client = {})",
+                transaction_type,
+                client
+            ));
+        }
+
+        let undo = if self.undo_depth > 0 {
+            let transaction = transaction.transaction_id();
+            Some(UndoEntry {
+                client,
+                account: self.account_store.snapshot(client, transaction),
+                transaction,
+                stored: self.transaction_store.snapshot(transaction),
+                flows: self.flows.clone(),
+                disputes: self.dispute_counts.get(&client).copied(),
+            })
+        } else {
+            None
+        };
+
+        let result = self.dispatch(transaction);
+        if result.is_err()
+            && matches!(
+                transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        {
+            if let Some(rejected_clients) = &mut self.rejected_clients {
+                rejected_clients.insert(client);
+            }
+        }
+
+        if let (Ok(()), Some(undo)) = (&result, undo) {
+            if self.undo_stack.len() == self.undo_depth {
+                self.undo_stack.pop_front();
+            }
+            self.undo_stack.push_back(undo);
+        }
+        result
+    }
+
+    /// Revert the latest applied transaction that has not been undone yet
+    /// Requires `HandlerConfig::undo_depth`, statistics and liquidity samples are not reverted.
+    pub fn undo_last(&mut self) -> Result<()> {
+        let undo = self
+            .undo_stack
+            .pop_back()
+            .ok_or_else(|| anyhow!("No transaction to undo"))?;
+
+        if let Err(error) = self
+            .transaction_store
+            .restore(undo.transaction, undo.stored.clone())
+        {
+            self.undo_stack.push_back(undo);
+            return Err(error);
+        }
+        self.account_store
+            .restore(undo.client, undo.transaction, undo.account);
+        self.flows = undo.flows;
+        match undo.disputes {
+            Some(disputes) => self.dispute_counts.insert(undo.client, disputes),
+            None => self.dispute_counts.remove(&undo.client),
+        };
+        Ok(())
+    }
+
+    /// Pass the transaction on to the matching `handle_*` method
+    fn dispatch(&mut self, transaction: Transaction) -> Result<()> {
+        match transaction {
+            Transaction::Deposit(mut record) => {
+                record.validate(&self.validation)?;
+                record.amount = self
+                    .sub_minor_units
+                    .apply(record.amount, record.transaction)?;
+                self.handle_deposit(record)
+            }
+            Transaction::Withdrawal(mut record) => {
+                record.validate(&self.validation)?;
+                record.amount = self
+                    .sub_minor_units
+                    .apply(record.amount, record.transaction)?;
+                self.handle_withdrawal(record)
+            }
+            Transaction::Dispute(record) => self.handle_dispute(record),
+            Transaction::Resolve(record) => self.handle_resolve(record),
+            Transaction::Chargeback(record) => self.handle_chargeback(record),
+        }
+    }
+
+    /// Number of currently disputed transactions of each client (clients without any are missing)
+    pub fn open_disputes(&self) -> HashMap<ClientId, usize> {
+        let mut counts = HashMap::new();
+        for stored in self.transaction_store.stored_transactions() {
+            if stored.status == Some(DisputeStatus::Disputed) {
+                *counts.entry(stored.client).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// List every transaction that has ever been disputed together with its latest status
+    pub fn dispute_report(&self) -> Vec<DisputeOutcome> {
+        self.transaction_store.dispute_report()
+    }
+
+    /// All transactions rejected by `handle_transactions` so far (in processing order)
+    /// Always empty unless `HandlerConfig::collect_rejections` is enabled.
+    pub fn rejections(&self) -> &[Rejection] {
+        self.rejections.as_deref().unwrap_or_default()
+    }
+
+    /// Clients that appeared in rejected deposits or withdrawals but have no account (sorted by ID)
+    /// Always empty unless `HandlerConfig::track_phantom_clients` is enabled.
+    pub fn phantom_clients(&self) -> Vec<ClientId> {
+        let mut clients: Vec<_> = self
+            .rejected_clients
+            .iter()
+            .flatten()
+            .copied()
+            .filter(|client| !self.account_store.has_account(*client))
+            .collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// List all clients whose available funds, held funds, or lock state differ between the two
+    /// handlers (sorted by client ID)
+    /// An account that only exists in one of the handlers is a difference as well. This allows
+    /// comparing the results of different processing modes or the state before and after a set of
+    /// transactions.
+    pub fn diff<T>(&self, other: &TransactionHandler<T>) -> Vec<AccountDiff> {
+        let mut accounts: BTreeMap<ClientId, (Option<Account>, Option<Account>)> = BTreeMap::new();
+        for account in self.account_store.accounts() {
+            let client = account.client;
+            accounts.entry(client).or_default().0 = Some(account);
+        }
+        for account in other.account_store.accounts() {
+            let client = account.client;
+            accounts.entry(client).or_default().1 = Some(account);
+        }
+
+        accounts
+            .into_iter()
+            .filter(|(_, (own, other))| own != other)
+            .map(|(client, (own, other))| AccountDiff { client, own, other })
+            .collect()
+    }
+
+    /// Number of client accounts
+    pub fn account_count(&self) -> usize {
+        self.account_store.len()
+    }
+
+    /// List all deposits retained for potential disputes with their dispute status (e.g. for an audit)
+    pub fn transaction_audit(&self) -> Vec<StoredTransaction> {
+        self.transaction_store.stored_transactions()
+    }
+
+    /// Number of deposits retained for potential disputes (e.g. to observe memory pressure)
+    pub fn stored_transactions(&self) -> usize {
+        self.transaction_store.len()
+    }
+
+    /// Record the current liquidity (computed from the flows, not by scanning all accounts)
+    fn sample_liquidity(&mut self, processed: usize) {
+        let MoneyFlows {
+            opening,
+            deposits,
+            withdrawals,
+            charged_back,
+            held,
+        } = self.flows.clone();
+        self.liquidity_series.push(LiquiditySample {
+            processed,
+            total_available: opening + deposits - withdrawals - charged_back - held,
+            total_held: held,
+        });
+    }
+
+    /// Liquidity recorded every `HandlerConfig::liquidity_sample_interval` transactions so far
+    pub fn liquidity_series(&self) -> &[LiquiditySample] {
+        &self.liquidity_series
+    }
+
+    /// How many transactions `handle_transactions` has applied or rejected so far
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+
+    /// Check whether all applied money movements add up to the current account balances
+    /// A mismatch between computed and observed total means that money got lost (or created).
+    pub fn reconcile(&mut self) -> Reconciliation {
+        let mut total_held = Amount::default();
+        let mut observed_total = Amount::default();
+        for account in self.account_store.into_iter() {
+            total_held += account.held;
+            observed_total += account.total();
+        }
+
+        let MoneyFlows {
+            opening,
+            deposits,
+            withdrawals,
+            charged_back,
+            held: _,
+        } = self.flows.clone();
+        Reconciliation {
+            opening_total: opening,
+            total_deposits: deposits,
+            total_withdrawals: withdrawals,
+            total_held,
+            total_charged_back: charged_back,
+            computed_total: opening + deposits - withdrawals - charged_back,
+            observed_total,
+        }
+    }
+
+    /// Apply a single transaction (or count the error reading it) and update the statistics
+    fn handle_transaction(&mut self, transaction: Result<Transaction>) {
+        let identity = transaction.as_ref().ok().map(|transaction| {
+            (
+                transaction.transaction_type(),
+                transaction.client(),
+                transaction.transaction_id(),
+            )
+        });
+
+        let position = self.statistics.applied + self.statistics.rejected + 1;
+        match &transaction {
+            Ok(transaction) => trace!("Processing #{}: {:?}", position, transaction),
+            Err(_) => trace!("Processing #{}: unparsable", position),
+        }
+
+        let result = transaction.and_then(|transaction| self.apply(transaction));
+        match result {
+            Ok(()) => {
+                self.statistics.applied += 1;
+                trace!("Outcome #{}: applied", position);
+            }
+            Err(error) => {
+                self.statistics.rejected += 1;
+                trace!("Outcome #{}: error ({:#})", position, error);
+                warn!("{:#}", error);
+
+                if let Some(rejections) = &mut self.rejections {
+                    rejections.push(Rejection {
+                        position,
+                        transaction_type: identity.map(|(transaction_type, _, _)| transaction_type),
+                        client: identity.map(|(_, client, _)| client),
+                        transaction: identity.map(|(_, _, transaction)| transaction),
+                        error: format!("{:#}", error),
+                    });
+                }
+            }
+        }
+
+        if let Some(interval) = self.liquidity_sample_interval {
+            let processed = self.statistics.applied + self.statistics.rejected;
+            if processed.is_multiple_of(interval) {
+                self.sample_liquidity(processed);
+            }
+        }
+
+        if let Some(interval) = self.commit_interval {
+            let processed = self.statistics.applied + self.statistics.rejected;
+            if processed.is_multiple_of(interval) {
+                self.commit();
+            }
+        }
+    }
+
+    /// Commit the transaction store, failures can only be reported as a warning
+    fn commit(&mut self) {
+        if let Err(error) = self.transaction_store.commit() {
+            warn!("Cannot commit transaction store: {:#}", error);
+        }
+    }
+
+    /// Handle at most `n` transactions of the iterator and return how many have been handled
+    /// Call it again to resume with the rest, e.g. to interleave processing with other work.
+    /// Unlike `handle_transactions`, this ignores `HandlerConfig::two_pass` and does not commit
+    /// the transaction store at the end (there is no end known here).
+    pub fn handle_n(
+        &mut self,
+        transactions: &mut impl Iterator<Item = Result<Transaction>>,
+        n: usize,
+    ) -> usize {
+        let mut handled = 0;
+        for transaction in transactions.take(n) {
+            self.handle_transaction(transaction);
+            handled += 1;
+        }
+        handled
+    }
+
     /// Handle all given transactions
     /// This method is infallible, all bogus transactions are ignored, errors will be logged.
     pub fn handle_transactions(&mut self, transactions: impl Iterator<Item = Result<Transaction>>) {
-        for transaction in transactions {
-            let result = transaction.and_then(|transaction| match transaction {
-                Transaction::Deposit(record) => self.handle_deposit(record),
-                Transaction::Withdrawal(record) => self.handle_withdrawal(record),
-                Transaction::Dispute(record) => self.handle_dispute(record),
-                Transaction::Resolve(record) => self.handle_resolve(record),
-                Transaction::Chargeback(record) => self.handle_chargeback(record),
+        if self.two_pass {
+            let (disputes, monetary): (Vec<_>, Vec<_>) = transactions.partition(|transaction| {
+                matches!(
+                    transaction,
+                    Ok(Transaction::Dispute(_))
+                        | Ok(Transaction::Resolve(_))
+                        | Ok(Transaction::Chargeback(_))
+                )
             });
-            if let Err(error) = result {
-                warn!("{}", error);
+            for transaction in monetary.into_iter().chain(disputes) {
+                self.handle_transaction(transaction);
+            }
+        } else {
+            for transaction in transactions {
+                self.handle_transaction(transaction);
             }
         }
+
+        self.commit();
+
+        if self.transaction_store.is_empty() {
+            debug!("No transactions retained for disputes");
+        } else {
+            debug!(
+                "{} transactions retained for disputes",
+                self.transaction_store.len()
+            );
+        }
     }
 }
 
@@ -128,8 +999,10 @@ impl Default for TransactionHandler {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::amount;
+    use crate::test_support::capture_logs;
+    use crate::test_support::tx;
     use crate::types::*;
-    use rust_decimal_macros::dec;
 
     #[test]
     fn single_deposit() {
@@ -137,19 +1010,19 @@ mod tests {
 
         let transactions = vec![Transaction::Deposit(MonetaryTransactionRecord {
             client: 0,
-            transaction: 0,
-            amount: dec!(2),
+            transaction: tx(0),
+            amount: amount!(2),
         })];
 
-        handler.handle_transactions(transactions.into_iter().map(|t| Ok(t)));
+        handler.handle_transactions(transactions.into_iter().map(Ok));
 
         let accounts: Vec<_> = handler.into_iter().collect();
         assert_eq!(
             accounts,
             vec![Account {
                 client: 0,
-                available: dec!(2.0),
-                held: Amount::ZERO,
+                available: amount!(2.0),
+                held: Amount::default(),
                 locked: false,
             }]
         );
@@ -162,25 +1035,25 @@ mod tests {
         let transactions = vec![
             Transaction::Deposit(MonetaryTransactionRecord {
                 client: 0,
-                transaction: 0,
-                amount: dec!(2.0),
+                transaction: tx(0),
+                amount: amount!(2.0),
             }),
             Transaction::Withdrawal(MonetaryTransactionRecord {
                 client: 0,
-                transaction: 0,
-                amount: dec!(1.0),
+                transaction: tx(0),
+                amount: amount!(1.0),
             }),
         ];
 
-        handler.handle_transactions(transactions.into_iter().map(|t| Ok(t)));
+        handler.handle_transactions(transactions.into_iter().map(Ok));
 
         let accounts: Vec<_> = handler.into_iter().collect();
         assert_eq!(
             accounts,
             vec![Account {
                 client: 0,
-                available: dec!(1.0),
-                held: Amount::ZERO,
+                available: amount!(1.0),
+                held: Amount::default(),
                 locked: false,
             }]
         );
@@ -193,25 +1066,25 @@ mod tests {
         let transactions = vec![
             Transaction::Deposit(MonetaryTransactionRecord {
                 client: 0,
-                transaction: 0,
-                amount: dec!(2.0),
+                transaction: tx(0),
+                amount: amount!(2.0),
             }),
             Transaction::Withdrawal(MonetaryTransactionRecord {
                 client: 0,
-                transaction: 0,
-                amount: dec!(3.0),
+                transaction: tx(0),
+                amount: amount!(3.0),
             }),
         ];
 
-        handler.handle_transactions(transactions.into_iter().map(|t| Ok(t)));
+        handler.handle_transactions(transactions.into_iter().map(Ok));
 
         let accounts: Vec<_> = handler.into_iter().collect();
         assert_eq!(
             accounts,
             vec![Account {
                 client: 0,
-                available: dec!(2.0),
-                held: Amount::ZERO,
+                available: amount!(2.0),
+                held: Amount::default(),
                 locked: false,
             }]
         );
@@ -224,29 +1097,29 @@ mod tests {
         let transactions = vec![
             Transaction::Deposit(MonetaryTransactionRecord {
                 client: 0,
-                transaction: 0,
-                amount: dec!(2.0),
+                transaction: tx(0),
+                amount: amount!(2.0),
             }),
             Transaction::Deposit(MonetaryTransactionRecord {
                 client: 0,
-                transaction: 1,
-                amount: dec!(3.0),
+                transaction: tx(1),
+                amount: amount!(3.0),
             }),
             Transaction::Dispute(DisputedTransactionRecord {
                 client: 0,
-                transaction: 1,
+                transaction: tx(1),
             }),
         ];
 
-        handler.handle_transactions(transactions.into_iter().map(|t| Ok(t)));
+        handler.handle_transactions(transactions.into_iter().map(Ok));
 
         let accounts: Vec<_> = handler.into_iter().collect();
         assert_eq!(
             accounts,
             vec![Account {
                 client: 0,
-                available: dec!(2.0),
-                held: dec!(3.0),
+                available: amount!(2.0),
+                held: amount!(3.0),
                 locked: false,
             }]
         );
@@ -259,28 +1132,28 @@ mod tests {
         let transactions = vec![
             Transaction::Deposit(MonetaryTransactionRecord {
                 client: 0,
-                transaction: 0,
-                amount: dec!(2.0),
+                transaction: tx(0),
+                amount: amount!(2.0),
             }),
             Transaction::Dispute(DisputedTransactionRecord {
                 client: 0,
-                transaction: 0,
+                transaction: tx(0),
             }),
             Transaction::Resolve(DisputedTransactionRecord {
                 client: 0,
-                transaction: 0,
+                transaction: tx(0),
             }),
         ];
 
-        handler.handle_transactions(transactions.into_iter().map(|t| Ok(t)));
+        handler.handle_transactions(transactions.into_iter().map(Ok));
 
         let accounts: Vec<_> = handler.into_iter().collect();
         assert_eq!(
             accounts,
             vec![Account {
                 client: 0,
-                available: dec!(2.0),
-                held: Amount::ZERO,
+                available: amount!(2.0),
+                held: Amount::default(),
                 locked: false,
             }]
         );
@@ -293,30 +1166,1776 @@ mod tests {
         let transactions = vec![
             Transaction::Deposit(MonetaryTransactionRecord {
                 client: 0,
-                transaction: 0,
-                amount: dec!(2.0),
+                transaction: tx(0),
+                amount: amount!(2.0),
             }),
             Transaction::Dispute(DisputedTransactionRecord {
                 client: 0,
-                transaction: 0,
+                transaction: tx(0),
             }),
             Transaction::Chargeback(DisputedTransactionRecord {
                 client: 0,
-                transaction: 0,
+                transaction: tx(0),
             }),
         ];
 
-        handler.handle_transactions(transactions.into_iter().map(|t| Ok(t)));
+        handler.handle_transactions(transactions.into_iter().map(Ok));
 
         let accounts: Vec<_> = handler.into_iter().collect();
         assert_eq!(
             accounts,
             vec![Account {
                 client: 0,
-                available: Amount::ZERO,
-                held: Amount::ZERO,
+                available: Amount::default(),
+                held: Amount::default(),
                 locked: true,
             }]
         );
     }
+
+    #[test]
+    fn resolve_with_mismatching_client() {
+        let mut handler = TransactionHandler::new();
+
+        let transactions = vec![
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+                amount: amount!(2.0),
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(1),
+                amount: amount!(1.0),
+            }),
+            Transaction::Dispute(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        let error = handler
+            .handle_resolve(DisputedTransactionRecord {
+                client: 1,
+                transaction: tx(0),
+            })
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<TransactionError>(),
+            Some(&TransactionError::ResolveClientMismatch {
+                transaction: tx(0),
+                expected: 0,
+                found: 1,
+            })
+        );
+
+        // the held funds stay with the original depositor
+        let mut accounts: Vec<_> = handler.into_iter().collect();
+        accounts.sort_by_key(|account| account.client);
+        assert_eq!(
+            accounts,
+            vec![
+                Account {
+                    client: 0,
+                    available: Amount::default(),
+                    held: amount!(2.0),
+                    locked: false,
+                },
+                Account {
+                    client: 1,
+                    available: amount!(1.0),
+                    held: Amount::default(),
+                    locked: false,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_single_transactions() {
+        let mut handler = TransactionHandler::new();
+
+        handler
+            .apply(Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+                amount: amount!(2.0),
+            }))
+            .unwrap();
+
+        // insufficient funds are reported instead of being logged
+        handler
+            .apply(Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(3.0),
+            }))
+            .unwrap_err();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(
+            accounts,
+            vec![Account {
+                client: 0,
+                available: amount!(2.0),
+                held: Amount::default(),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn dispute_report_with_all_states() {
+        let mut handler = TransactionHandler::new();
+
+        let deposit = |client, transaction| {
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client,
+                transaction,
+                amount: amount!(1.0),
+            })
+        };
+        let disputed = |client, transaction| DisputedTransactionRecord {
+            client,
+            transaction,
+        };
+        let transactions = vec![
+            deposit(0, tx(0)),
+            deposit(0, tx(1)),
+            deposit(1, tx(2)),
+            deposit(1, tx(3)),
+            Transaction::Dispute(disputed(0, tx(1))),
+            Transaction::Dispute(disputed(1, tx(2))),
+            Transaction::Resolve(disputed(1, tx(2))),
+            Transaction::Dispute(disputed(1, tx(3))),
+            Transaction::Chargeback(disputed(1, tx(3))),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        assert_eq!(
+            handler.dispute_report(),
+            vec![
+                DisputeOutcome {
+                    client: 0,
+                    transaction: tx(1),
+                    status: DisputeStatus::Disputed,
+                },
+                DisputeOutcome {
+                    client: 1,
+                    transaction: tx(2),
+                    status: DisputeStatus::Resolved,
+                },
+                DisputeOutcome {
+                    client: 1,
+                    transaction: tx(3),
+                    status: DisputeStatus::ChargedBack,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn withdraw_with_held_funds() {
+        let mut handler = TransactionHandler::new();
+
+        let transactions = vec![
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+                amount: amount!(6.0),
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(4.0),
+            }),
+            Transaction::Dispute(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        // only the available funds count, not available + held
+        let withdrawal = |transaction, amount| {
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction,
+                amount,
+            })
+        };
+        handler.apply(withdrawal(tx(2), amount!(10.0))).unwrap_err();
+        handler.apply(withdrawal(tx(3), amount!(7.0))).unwrap_err();
+        handler.apply(withdrawal(tx(4), amount!(6.0))).unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(
+            accounts,
+            vec![Account {
+                client: 0,
+                available: Amount::default(),
+                held: amount!(4.0),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn dispute_zero_amount_deposit() {
+        let mut handler = TransactionHandler::new();
+
+        let transactions = vec![
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+                amount: amount!(1.0),
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: Amount::default(),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        handler
+            .apply(Transaction::Dispute(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }))
+            .unwrap();
+
+        // the transaction was never marked as disputed
+        assert_eq!(handler.dispute_report(), vec![]);
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(
+            accounts,
+            vec![Account {
+                client: 0,
+                available: amount!(1.0),
+                held: Amount::default(),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn reconcile_known_input() {
+        let mut handler = TransactionHandler::new();
+
+        let transactions = vec![
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+                amount: amount!(10.0),
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(1),
+                amount: amount!(4.0),
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(2),
+                amount: amount!(3.0),
+            }),
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(3),
+                amount: amount!(2.5),
+            }),
+            // rejected, does not count
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(4),
+                amount: amount!(100.0),
+            }),
+            Transaction::Dispute(DisputedTransactionRecord {
+                client: 1,
+                transaction: tx(1),
+            }),
+            Transaction::Dispute(DisputedTransactionRecord {
+                client: 1,
+                transaction: tx(2),
+            }),
+            Transaction::Chargeback(DisputedTransactionRecord {
+                client: 1,
+                transaction: tx(2),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        assert_eq!(
+            handler.reconcile(),
+            Reconciliation {
+                opening_total: amount!(0),
+                total_deposits: amount!(17.0),
+                total_withdrawals: amount!(2.5),
+                total_held: amount!(4.0),
+                total_charged_back: amount!(3.0),
+                computed_total: amount!(11.5),
+                observed_total: amount!(11.5),
+            }
+        );
+    }
+
+    #[test]
+    fn clone_diverges_independently() {
+        let mut handler = TransactionHandler::new();
+
+        handler
+            .apply(Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+                amount: amount!(5.0),
+            }))
+            .unwrap();
+
+        let mut snapshot = handler.clone();
+
+        handler
+            .apply(Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(2.0),
+            }))
+            .unwrap();
+        snapshot
+            .apply(Transaction::Dispute(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+            }))
+            .unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(
+            accounts,
+            vec![Account {
+                client: 0,
+                available: amount!(3.0),
+                held: Amount::default(),
+                locked: false,
+            }]
+        );
+        assert_eq!(handler.dispute_report(), vec![]);
+
+        let accounts: Vec<_> = snapshot.into_iter().collect();
+        assert_eq!(
+            accounts,
+            vec![Account {
+                client: 0,
+                available: Amount::default(),
+                held: amount!(5.0),
+                locked: false,
+            }]
+        );
+        assert_eq!(snapshot.dispute_report().len(), 1);
+    }
+
+    #[test]
+    fn statistics() {
+        let mut handler = TransactionHandler::new();
+
+        let deposit = |transaction, amount| {
+            Ok(Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction,
+                amount,
+            }))
+        };
+        let transactions = vec![
+            deposit(tx(0), amount!(1.0)),
+            deposit(tx(0), amount!(1.0)), // duplicate
+            Err(anyhow::anyhow!("unparsable")),
+            deposit(tx(1), amount!(1.0)),
+        ];
+        handler.handle_transactions(transactions.into_iter());
+
+        assert_eq!(
+            handler.statistics(),
+            &Statistics {
+                applied: 2,
+                rejected: 2,
+            }
+        );
+        assert_eq!(handler.statistics().rejected_ratio(), 0.5);
+        assert_eq!(Statistics::default().rejected_ratio(), 0.0);
+    }
+
+    #[test]
+    fn resolve_does_not_release_other_dispute() {
+        let mut handler = TransactionHandler::new();
+
+        let disputed = |transaction| DisputedTransactionRecord {
+            client: 0,
+            transaction,
+        };
+        let transactions = vec![
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+                amount: amount!(5.0),
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(5.0),
+            }),
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+                amount: amount!(8.0),
+            }),
+            // both disputes hold their full amount, even though only 2.0 are available
+            Transaction::Dispute(disputed(tx(0))),
+            Transaction::Dispute(disputed(tx(1))),
+            Transaction::Resolve(disputed(tx(1))),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(
+            accounts,
+            vec![Account {
+                client: 0,
+                available: amount!(-3.0),
+                held: amount!(5.0),
+                locked: false,
+            }]
+        );
+
+        handler
+            .apply(Transaction::Resolve(disputed(tx(0))))
+            .unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(
+            accounts,
+            vec![Account {
+                client: 0,
+                available: amount!(2.0),
+                held: Amount::default(),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn transaction_store_limit() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            max_stored_transactions: Some(1),
+            ..HandlerConfig::default()
+        });
+
+        let deposit = |transaction, amount| {
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction,
+                amount,
+            })
+        };
+        let dispute = |transaction| {
+            Transaction::Dispute(DisputedTransactionRecord {
+                client: 0,
+                transaction,
+            })
+        };
+        handler.apply(deposit(tx(0), amount!(1.0))).unwrap();
+        handler.apply(deposit(tx(1), amount!(2.0))).unwrap();
+
+        // the second deposit counts but was not stored for disputes
+        handler.apply(dispute(tx(1))).unwrap_err();
+        handler.apply(dispute(tx(0))).unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(
+            accounts,
+            vec![Account {
+                client: 0,
+                available: amount!(2.0),
+                held: amount!(1.0),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn duplicate_deposit_beyond_transaction_store_limit() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            max_stored_transactions: Some(1),
+            ..HandlerConfig::default()
+        });
+
+        handler.apply(deposit(tx(0), amount!(1.0))).unwrap();
+        handler.apply(deposit(tx(1), amount!(2.0))).unwrap();
+
+        // only the stored deposit is known, the one beyond the limit is credited again
+        handler.apply(deposit(tx(0), amount!(1.0))).unwrap_err();
+        handler.apply(deposit(tx(1), amount!(2.0))).unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(5.0));
+    }
+
+    #[test]
+    fn validated_amounts() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            validation: ValidationConfig {
+                max_amount: Some(amount!(100)),
+                ..ValidationConfig::default()
+            },
+            ..HandlerConfig::default()
+        });
+
+        handler
+            .apply(Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(100),
+            }))
+            .unwrap();
+        let error = handler
+            .apply(Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+                amount: amount!(100.5),
+            }))
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&TransactionError::AmountOutOfRange {
+                transaction: tx(2),
+                max_amount: amount!(100)
+            })
+        );
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(100));
+    }
+
+    fn deposit(transaction: TransactionId, amount: Amount) -> Transaction {
+        Transaction::Deposit(MonetaryTransactionRecord {
+            client: 0,
+            transaction,
+            amount,
+        })
+    }
+
+    fn dispute(transaction: TransactionId) -> Transaction {
+        Transaction::Dispute(DisputedTransactionRecord {
+            client: 0,
+            transaction,
+        })
+    }
+
+    #[test]
+    fn dispute_adjacent_to_deposit() {
+        let mut handler = TransactionHandler::new();
+
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+        handler.apply(dispute(tx(1))).unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(0));
+        assert_eq!(accounts[0].held, amount!(5));
+    }
+
+    #[test]
+    fn stored_transactions() {
+        let mut handler = TransactionHandler::new();
+
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+        handler.apply(deposit(tx(2), amount!(5))).unwrap();
+        handler.apply(dispute(tx(1))).unwrap();
+        assert_eq!(handler.stored_transactions(), 2);
+    }
+
+    #[test]
+    fn dispute_distant_from_deposit() {
+        let mut handler = TransactionHandler::new();
+
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+        for transaction in 2..1000 {
+            handler.apply(deposit(tx(transaction), amount!(1))).unwrap();
+        }
+        handler.apply(dispute(tx(1))).unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].held, amount!(5));
+    }
+
+    #[test]
+    fn dispute_before_deposit() {
+        let mut handler = TransactionHandler::new();
+
+        let error = handler.apply(dispute(tx(1))).unwrap_err();
+        assert!(error.to_string().contains("not found"));
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(5));
+        assert_eq!(accounts[0].held, amount!(0));
+    }
+
+    #[test]
+    fn reserved_client() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            reserved_clients: vec![0].into_iter().collect(),
+            ..HandlerConfig::default()
+        });
+
+        let transactions = vec![
+            deposit(tx(1), amount!(5)),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(2),
+                amount: amount!(3),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+        assert_eq!(handler.statistics().rejected, 1);
+
+        let error = handler.apply(dispute(tx(1))).unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&TransactionError::ReservedClient { client: 0 })
+        );
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(
+            accounts,
+            vec![Account {
+                client: 1,
+                available: amount!(3),
+                held: Amount::default(),
+                locked: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn liquidity_series() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            liquidity_sample_interval: Some(2),
+            ..HandlerConfig::default()
+        });
+
+        let transactions = vec![
+            deposit(tx(1), amount!(5)),
+            deposit(tx(2), amount!(3)),
+            dispute(tx(1)),
+            dispute(tx(3)), // rejected, still counts as processed
+            Transaction::Resolve(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        assert_eq!(
+            handler.liquidity_series(),
+            &[
+                LiquiditySample {
+                    processed: 2,
+                    total_available: amount!(8),
+                    total_held: amount!(0),
+                },
+                LiquiditySample {
+                    processed: 4,
+                    total_available: amount!(3),
+                    total_held: amount!(5),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_withdrawals() {
+        let allowed_types = vec![
+            TransactionType::Deposit,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ];
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            allowed_types: Some(allowed_types.into_iter().collect()),
+            ..HandlerConfig::default()
+        });
+
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+        let error = handler
+            .apply(Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+                amount: amount!(1),
+            }))
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&TransactionError::TransactionTypeDisabled {
+                transaction_type: TransactionType::Withdrawal
+            })
+        );
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(5));
+    }
+
+    #[test]
+    fn phantom_clients() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            track_phantom_clients: true,
+            ..HandlerConfig::default()
+        });
+
+        let transactions = vec![
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(1),
+                amount: amount!(1),
+            }),
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 2,
+                transaction: tx(2),
+                amount: amount!(1),
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 2,
+                transaction: tx(3),
+                amount: amount!(1),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        assert_eq!(handler.phantom_clients(), vec![1]);
+        assert!(TransactionHandler::new().phantom_clients().is_empty());
+    }
+
+    fn locked_with_open_dispute(strict_freeze: bool) -> TransactionHandler {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            strict_freeze,
+            ..HandlerConfig::default()
+        });
+
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+        handler.apply(deposit(tx(2), amount!(3))).unwrap();
+        handler.apply(deposit(tx(3), amount!(2))).unwrap();
+        handler.apply(dispute(tx(2))).unwrap();
+        handler.apply(dispute(tx(1))).unwrap();
+        handler
+            .apply(Transaction::Chargeback(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }))
+            .unwrap();
+        handler
+    }
+
+    #[test]
+    fn disputes_on_locked_account() {
+        let mut handler = locked_with_open_dispute(false);
+
+        handler
+            .apply(Transaction::Resolve(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+            }))
+            .unwrap();
+        handler.apply(dispute(tx(3))).unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(3));
+        assert_eq!(accounts[0].held, amount!(2));
+        assert!(accounts[0].locked);
+    }
+
+    #[test]
+    fn disputes_on_locked_account_strict_freeze() {
+        let mut handler = locked_with_open_dispute(true);
+
+        handler
+            .apply(Transaction::Resolve(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+            }))
+            .unwrap_err();
+        handler.apply(dispute(tx(3))).unwrap_err();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(2));
+        assert_eq!(accounts[0].held, amount!(3));
+        assert!(accounts[0].locked);
+    }
+
+    #[test]
+    fn opening_balances() {
+        let opening = vec![
+            Account {
+                client: 0,
+                available: amount!(10),
+                held: amount!(2),
+                locked: false,
+            },
+            Account {
+                client: 1,
+                available: amount!(1),
+                held: amount!(0),
+                locked: true,
+            },
+        ];
+        let mut handler = TransactionHandler::with_opening_balances(opening).unwrap();
+
+        let transactions = vec![
+            deposit(tx(1), amount!(5)),
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+                amount: amount!(15),
+            }),
+            // locked yesterday, still locked today
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(3),
+                amount: amount!(1),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        let reconciliation = handler.reconcile();
+        assert_eq!(reconciliation.opening_total, amount!(13));
+        assert_eq!(reconciliation.computed_total, reconciliation.observed_total);
+
+        let mut accounts: Vec<_> = handler.into_iter().collect();
+        accounts.sort_by_key(|account| account.client);
+        assert_eq!(
+            accounts,
+            vec![
+                Account {
+                    client: 0,
+                    available: amount!(0),
+                    held: amount!(2),
+                    locked: false,
+                },
+                Account {
+                    client: 1,
+                    available: amount!(1),
+                    held: amount!(0),
+                    locked: true,
+                },
+            ]
+        );
+
+        let duplicate = vec![opening_account(0), opening_account(0)];
+        assert!(TransactionHandler::with_opening_balances(duplicate).is_err());
+    }
+
+    fn opening_account(client: ClientId) -> Account {
+        Account {
+            client,
+            available: amount!(1),
+            held: amount!(0),
+            locked: false,
+        }
+    }
+
+    #[test]
+    fn account_count() {
+        let mut handler = TransactionHandler::new();
+        assert_eq!(handler.account_count(), 0);
+
+        for client in 0..3 {
+            handler
+                .apply(Transaction::Deposit(MonetaryTransactionRecord {
+                    client,
+                    transaction: tx(client.into()),
+                    amount: amount!(1),
+                }))
+                .unwrap();
+        }
+        handler.apply(deposit(tx(10), amount!(1))).unwrap();
+        assert_eq!(handler.account_count(), 3);
+    }
+
+    fn empty_chargeback(skip_lock_on_empty_chargeback: bool) -> Account {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            skip_lock_on_empty_chargeback,
+            dispute_cap: Some(DisputeCap {
+                max_amount: amount!(0),
+                mode: DisputeCapMode::Hold,
+            }),
+            ..HandlerConfig::default()
+        });
+
+        let transactions = vec![
+            deposit(tx(1), amount!(5)),
+            dispute(tx(1)), // the cap leaves nothing to hold
+            Transaction::Chargeback(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+        assert_eq!(handler.statistics().rejected, 0);
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        accounts[0].clone()
+    }
+
+    #[test]
+    fn chargeback_without_held_funds() {
+        let account = empty_chargeback(false);
+        assert_eq!(account.total(), amount!(5));
+        assert!(account.locked);
+
+        let account = empty_chargeback(true);
+        assert_eq!(account.total(), amount!(5));
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn undo_last() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            undo_depth: 2,
+            ..HandlerConfig::default()
+        });
+
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+        handler.apply(dispute(tx(1))).unwrap();
+        handler
+            .apply(Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+                amount: amount!(1),
+            }))
+            .unwrap_err(); // not undoable, nothing happened
+        handler
+            .apply(Transaction::Resolve(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }))
+            .unwrap();
+        handler
+            .apply(Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+                amount: amount!(2),
+            }))
+            .unwrap();
+
+        handler.undo_last().unwrap();
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(5));
+
+        // the dispute can be resolved again after undoing the resolve
+        handler.undo_last().unwrap();
+        handler.undo_last().unwrap_err(); // depth exceeded
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(0));
+        assert_eq!(accounts[0].held, amount!(5));
+        handler
+            .apply(Transaction::Resolve(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }))
+            .unwrap();
+
+        let reconciliation = handler.reconcile();
+        assert_eq!(reconciliation.total_withdrawals, amount!(0));
+        assert_eq!(reconciliation.computed_total, reconciliation.observed_total);
+
+        TransactionHandler::new().undo_last().unwrap_err();
+    }
+
+    #[test]
+    fn dispute_after_chargeback() {
+        for freeze_after_chargeback in [false, true] {
+            let mut handler = TransactionHandler::with_config(HandlerConfig {
+                freeze_after_chargeback,
+                ..HandlerConfig::default()
+            });
+
+            handler.apply(deposit(tx(1), amount!(5))).unwrap();
+            handler.apply(deposit(tx(2), amount!(3))).unwrap();
+            handler.apply(dispute(tx(1))).unwrap();
+            handler
+                .apply(Transaction::Chargeback(DisputedTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                }))
+                .unwrap();
+
+            let result = handler.apply(dispute(tx(2)));
+            let accounts: Vec<_> = handler.into_iter().collect();
+            if freeze_after_chargeback {
+                assert_eq!(
+                    result.unwrap_err().downcast_ref::<TransactionError>(),
+                    Some(&TransactionError::FrozenByChargeback {
+                        client: 0,
+                        transaction: tx(2),
+                        prior_transaction: Box::new(tx(1))
+                    })
+                );
+                assert_eq!(accounts[0].available, amount!(3));
+                assert_eq!(accounts[0].held, amount!(0));
+            } else {
+                result.unwrap();
+                assert_eq!(accounts[0].available, amount!(0));
+                assert_eq!(accounts[0].held, amount!(3));
+            }
+        }
+    }
+
+    #[test]
+    fn dispute_of_account_locked_in_opening_balance() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            freeze_after_chargeback: true,
+            ..HandlerConfig::default()
+        });
+        handler
+            .open_accounts(vec![Account {
+                client: 0,
+                available: amount!(5),
+                held: amount!(0),
+                locked: true,
+            }])
+            .unwrap();
+
+        // the chargeback that locked the account is unknown
+        assert_eq!(
+            handler
+                .apply(dispute(tx(1)))
+                .unwrap_err()
+                .downcast_ref::<TransactionError>(),
+            Some(&TransactionError::AccountFrozen {
+                client: 0,
+                transaction: tx(1)
+            })
+        );
+    }
+
+    #[test]
+    fn reference_withdrawal_or_unknown() {
+        let mut handler = TransactionHandler::new();
+        handler.apply(deposit(tx(0), amount!(5))).unwrap();
+        handler
+            .apply(Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(1),
+            }))
+            .unwrap();
+
+        let record = |transaction| DisputedTransactionRecord {
+            client: 0,
+            transaction,
+        };
+        for transaction in [tx(1), tx(2)] {
+            let expected = if transaction == tx(1) {
+                TransactionError::NotDisputable { transaction }
+            } else {
+                TransactionError::TransactionNotFound { transaction }
+            };
+            for reference in [
+                Transaction::Dispute(record(transaction)),
+                Transaction::Resolve(record(transaction)),
+                Transaction::Chargeback(record(transaction)),
+            ] {
+                let error = handler.apply(reference).unwrap_err();
+                assert_eq!(error.downcast_ref(), Some(&expected));
+            }
+        }
+    }
+
+    #[test]
+    fn shared_transaction_id() {
+        for unique_transaction_ids in [false, true] {
+            let mut handler = TransactionHandler::with_config(HandlerConfig {
+                unique_transaction_ids,
+                ..HandlerConfig::default()
+            });
+            let withdrawal = |transaction| {
+                Transaction::Withdrawal(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction,
+                    amount: amount!(1),
+                })
+            };
+
+            handler.apply(deposit(tx(0), amount!(5))).unwrap();
+            let results = [
+                handler.apply(withdrawal(tx(0))),
+                handler.apply(withdrawal(tx(1))),
+                handler.apply(withdrawal(tx(1))),
+                handler.apply(deposit(tx(1), amount!(2))),
+            ];
+
+            let accounts: Vec<_> = handler.into_iter().collect();
+            if unique_transaction_ids {
+                let errors: Vec<_> = results
+                    .iter()
+                    .map(|result| {
+                        result
+                            .as_ref()
+                            .err()
+                            .map(|error| error.downcast_ref::<TransactionError>().unwrap().clone())
+                    })
+                    .collect();
+                let duplicate =
+                    |transaction| Some(TransactionError::DuplicateTransaction { transaction });
+                assert_eq!(
+                    errors,
+                    vec![duplicate(tx(0)), None, duplicate(tx(1)), duplicate(tx(1))]
+                );
+                assert_eq!(accounts[0].available, amount!(4));
+            } else {
+                assert!(results.iter().all(|result| result.is_ok()));
+                assert_eq!(accounts[0].available, amount!(4));
+            }
+        }
+    }
+
+    #[test]
+    fn two_pass() {
+        let transactions = || {
+            vec![
+                Ok(dispute(tx(1))),
+                Ok(deposit(tx(1), amount!(5))),
+                Ok(Transaction::Chargeback(DisputedTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                })),
+                Ok(deposit(tx(2), amount!(3))),
+            ]
+            .into_iter()
+        };
+
+        let mut handler = TransactionHandler::new();
+        handler.handle_transactions(transactions());
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(8));
+        assert!(!accounts[0].locked);
+
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            two_pass: true,
+            ..HandlerConfig::default()
+        });
+        handler.handle_transactions(transactions());
+        assert_eq!(handler.statistics().rejected, 0);
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(3));
+        assert_eq!(accounts[0].held, amount!(0));
+        assert!(accounts[0].locked);
+    }
+
+    #[test]
+    fn collect_rejections() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            collect_rejections: true,
+            ..HandlerConfig::default()
+        });
+
+        handler.handle_transactions(
+            vec![
+                Ok(deposit(tx(1), amount!(5))),
+                Err(anyhow!("Invalid row")),
+                Ok(dispute(tx(7))),
+            ]
+            .into_iter(),
+        );
+
+        let rejections = handler.rejections();
+        assert_eq!(rejections.len(), 2);
+        assert_eq!(rejections[0].position, 2);
+        assert_eq!(rejections[0].transaction_type, None);
+        assert_eq!(rejections[0].error, "Invalid row");
+        assert_eq!(rejections[1].position, 3);
+        assert_eq!(
+            rejections[1].transaction_type,
+            Some(TransactionType::Dispute)
+        );
+        assert_eq!(rejections[1].client, Some(0));
+        assert_eq!(rejections[1].transaction, Some(tx(7)));
+
+        assert!(TransactionHandler::new().rejections().is_empty());
+    }
+
+    /// Timing based, run with `cargo test --release -- --ignored`
+    #[test]
+    #[ignore]
+    fn many_disputes_of_one_client_scale_linearly() {
+        let run = |count: u32| {
+            let mut handler = TransactionHandler::with_config(HandlerConfig {
+                undo_depth: 1,
+                ..HandlerConfig::default()
+            });
+            let start = std::time::Instant::now();
+            for transaction in 0..count {
+                handler.apply(deposit(tx(transaction), amount!(1))).unwrap();
+            }
+            for transaction in 0..count {
+                handler.apply(dispute(tx(transaction))).unwrap();
+            }
+            for transaction in 0..count {
+                handler
+                    .apply(Transaction::Resolve(DisputedTransactionRecord {
+                        client: 0,
+                        transaction: tx(transaction),
+                    }))
+                    .unwrap();
+            }
+            start.elapsed()
+        };
+
+        run(10_000); // warm-up
+        let small = run(50_000);
+        let large = run(200_000);
+        // 4x the transactions, quadratic behavior would take about 16x as long
+        assert!(large < small * 8, "{:?} vs. {:?}", small, large);
+    }
+
+    #[test]
+    fn handle_in_chunks() {
+        let transactions = || {
+            vec![
+                Ok(deposit(tx(1), amount!(5))),
+                Ok(deposit(tx(2), amount!(3))),
+                Err(anyhow!("Invalid row")),
+                Ok(dispute(tx(1))),
+                Ok(Transaction::Chargeback(DisputedTransactionRecord {
+                    client: 0,
+                    transaction: tx(1),
+                })),
+            ]
+            .into_iter()
+        };
+
+        let mut handler = TransactionHandler::new();
+        handler.handle_transactions(transactions());
+
+        let mut chunked = TransactionHandler::new();
+        let mut remaining = transactions();
+        let mut chunks = vec![];
+        loop {
+            let handled = chunked.handle_n(&mut remaining, 2);
+            if handled == 0 {
+                break;
+            }
+            chunks.push(handled);
+        }
+
+        assert_eq!(chunks, vec![2, 2, 1]);
+        assert_eq!(chunked.statistics(), handler.statistics());
+        let accounts: Vec<_> = handler.into_iter().collect();
+        let chunked_accounts: Vec<_> = chunked.into_iter().collect();
+        assert_eq!(chunked_accounts, accounts);
+    }
+
+    #[test]
+    fn double_resolve() {
+        for idempotent_resolve in [false, true] {
+            let mut handler = TransactionHandler::with_config(HandlerConfig {
+                idempotent_resolve,
+                ..HandlerConfig::default()
+            });
+            let resolve = |transaction| {
+                Transaction::Resolve(DisputedTransactionRecord {
+                    client: 0,
+                    transaction,
+                })
+            };
+
+            handler.apply(deposit(tx(1), amount!(5))).unwrap();
+            handler.apply(deposit(tx(2), amount!(3))).unwrap();
+            handler.apply(dispute(tx(1))).unwrap();
+            handler.apply(resolve(tx(1))).unwrap();
+            handler.apply(dispute(tx(2))).unwrap();
+
+            let result = handler.apply(resolve(tx(1)));
+            if idempotent_resolve {
+                result.unwrap();
+            } else {
+                assert_eq!(
+                    result.unwrap_err().downcast_ref::<TransactionError>(),
+                    Some(&TransactionError::NotCurrentlyDisputed {
+                        transaction: tx(1),
+                        status: DisputeStatus::Resolved
+                    })
+                );
+            }
+
+            let accounts: Vec<_> = handler.into_iter().collect();
+            assert_eq!(accounts[0].available, amount!(5));
+            assert_eq!(accounts[0].held, amount!(3));
+        }
+    }
+
+    #[test]
+    fn dispute_rate_limit() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            max_disputes_per_client: Some(3),
+            ..HandlerConfig::default()
+        });
+
+        for transaction in 1..=4 {
+            handler.apply(deposit(tx(transaction), amount!(1))).unwrap();
+        }
+        handler
+            .apply(Transaction::Deposit(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(5),
+                amount: amount!(1),
+            }))
+            .unwrap();
+
+        handler.apply(dispute(tx(7))).unwrap_err(); // failed disputes do not count
+        for transaction in 1..=3 {
+            handler.apply(dispute(tx(transaction))).unwrap();
+        }
+        let error = handler.apply(dispute(tx(4))).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<TransactionError>(),
+            Some(&TransactionError::DisputeRateLimited {
+                client: 0,
+                transaction: tx(4),
+                max_disputes: 3
+            })
+        );
+        handler
+            .apply(Transaction::Dispute(DisputedTransactionRecord {
+                client: 1,
+                transaction: tx(5),
+            }))
+            .unwrap();
+    }
+
+    #[test]
+    fn trace_every_transaction() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 1, 2.0
+withdrawal, 1, 2, 5.0
+"#;
+        let traces = capture_logs(log::Level::Trace, || {
+            let mut handler = TransactionHandler::new();
+            handler.handle_transactions(crate::csv_parser::iter_transactions(&source[..]));
+        });
+        assert_eq!(traces.len(), 4);
+        assert!(traces[0].starts_with("Processing #1: Deposit("));
+        assert_eq!(traces[1], "Outcome #1: applied");
+        assert!(traces[2].starts_with("Processing #2: Withdrawal("));
+        assert!(traces[3].starts_with("Outcome #2: error ("));
+    }
+
+    #[test]
+    fn dispute_cap() {
+        for mode in [DisputeCapMode::Reject, DisputeCapMode::Hold] {
+            let mut handler = TransactionHandler::with_config(HandlerConfig {
+                dispute_cap: Some(DisputeCap {
+                    max_amount: amount!(10),
+                    mode,
+                }),
+                ..HandlerConfig::default()
+            });
+
+            handler.apply(deposit(tx(1), amount!(10))).unwrap();
+            handler.apply(deposit(tx(2), amount!(25))).unwrap();
+            handler.apply(dispute(tx(1))).unwrap(); // at the cap, fully held
+
+            let result = handler.apply(dispute(tx(2)));
+            let accounts: Vec<_> = handler.into_iter().collect();
+            match mode {
+                DisputeCapMode::Reject => {
+                    assert_eq!(
+                        result.unwrap_err().downcast_ref::<TransactionError>(),
+                        Some(&TransactionError::DisputeAmountExceeded {
+                            transaction: tx(2),
+                            max_amount: amount!(10)
+                        })
+                    );
+                    assert_eq!(accounts[0].available, amount!(25));
+                    assert_eq!(accounts[0].held, amount!(10));
+                }
+                DisputeCapMode::Hold => {
+                    result.unwrap();
+                    assert_eq!(accounts[0].available, amount!(15));
+                    assert_eq!(accounts[0].held, amount!(20));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn redeposit_and_redispute_after_resolve() {
+        let mut handler = TransactionHandler::new();
+        let resolve = Transaction::Resolve(DisputedTransactionRecord {
+            client: 0,
+            transaction: tx(1),
+        });
+
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+        handler.apply(dispute(tx(1))).unwrap();
+        handler.apply(resolve.clone()).unwrap();
+
+        // the resolved transaction still exists, so its ID cannot be reused
+        handler.apply(deposit(tx(1), amount!(5))).unwrap_err();
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(5));
+
+        handler.apply(dispute(tx(1))).unwrap();
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(0));
+        assert_eq!(accounts[0].held, amount!(5));
+
+        handler.apply(resolve).unwrap();
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(5));
+        assert_eq!(accounts[0].held, amount!(0));
+    }
+
+    #[test]
+    fn rounded_hold_matches_rounded_deposit() {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            sub_minor_units: SubMinorUnitPolicy::Round,
+            ..HandlerConfig::default()
+        });
+
+        handler.apply(deposit(tx(1), amount!(1.00005))).unwrap();
+        handler.apply(dispute(tx(1))).unwrap();
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(0));
+        assert_eq!(accounts[0].held, amount!(1.0001));
+        assert_eq!(accounts[0].total(), amount!(1.0001));
+    }
+
+    #[test]
+    fn chargeback_without_lock() {
+        let chargeback = Transaction::Chargeback(DisputedTransactionRecord {
+            client: 0,
+            transaction: tx(1),
+        });
+        for skip_lock_on_chargeback in [false, true] {
+            let mut handler = TransactionHandler::with_config(HandlerConfig {
+                skip_lock_on_chargeback,
+                ..HandlerConfig::default()
+            });
+
+            handler.apply(deposit(tx(1), amount!(5))).unwrap();
+            handler.apply(deposit(tx(2), amount!(3))).unwrap();
+            handler.apply(dispute(tx(1))).unwrap();
+            handler.apply(chargeback.clone()).unwrap();
+
+            let accounts: Vec<_> = handler.into_iter().collect();
+            assert_eq!(accounts[0].available, amount!(3));
+            assert_eq!(accounts[0].held, amount!(0));
+            assert_eq!(accounts[0].locked, !skip_lock_on_chargeback);
+
+            // only an unlocked account can keep transacting
+            let result = handler.apply(deposit(tx(3), amount!(1)));
+            assert_eq!(result.is_ok(), skip_lock_on_chargeback);
+        }
+    }
+
+    #[test]
+    fn dispute_withdrawal() {
+        let record = DisputedTransactionRecord {
+            client: 0,
+            transaction: tx(2),
+        };
+        let withdrawal = Transaction::Withdrawal(MonetaryTransactionRecord {
+            client: 0,
+            transaction: tx(2),
+            amount: amount!(4),
+        });
+
+        // without the option, withdrawals are not disputable
+        let mut handler = TransactionHandler::new();
+        handler.apply(deposit(tx(1), amount!(10))).unwrap();
+        handler.apply(withdrawal.clone()).unwrap();
+        handler
+            .apply(Transaction::Dispute(record.clone()))
+            .unwrap_err();
+
+        for outcome in [UndisputeOutcome::Resolve, UndisputeOutcome::Chargeback] {
+            let mut handler = TransactionHandler::with_config(HandlerConfig {
+                disputable_withdrawals: true,
+                ..HandlerConfig::default()
+            });
+            handler.apply(deposit(tx(1), amount!(10))).unwrap();
+            handler.apply(withdrawal.clone()).unwrap();
+
+            // the withdrawn amount is held as it may have to be returned
+            handler.apply(Transaction::Dispute(record.clone())).unwrap();
+            let accounts: Vec<_> = handler.into_iter().collect();
+            assert_eq!(accounts[0].available, amount!(6));
+            assert_eq!(accounts[0].held, amount!(4));
+            let reconciliation = handler.reconcile();
+            assert_eq!(reconciliation.computed_total, reconciliation.observed_total);
+
+            let (transaction, available, locked) = match outcome {
+                UndisputeOutcome::Resolve => {
+                    (Transaction::Resolve(record.clone()), amount!(6), false)
+                }
+                UndisputeOutcome::Chargeback => {
+                    (Transaction::Chargeback(record.clone()), amount!(10), true)
+                }
+            };
+            handler.apply(transaction).unwrap();
+            let accounts: Vec<_> = handler.into_iter().collect();
+            assert_eq!(accounts[0].available, available);
+            assert_eq!(accounts[0].held, amount!(0));
+            assert_eq!(accounts[0].locked, locked);
+            let reconciliation = handler.reconcile();
+            assert_eq!(reconciliation.computed_total, reconciliation.observed_total);
+        }
+    }
+
+    #[test]
+    fn dispute_holds_full_amount() {
+        let mut handler = TransactionHandler::new();
+
+        let transactions = vec![
+            deposit(tx(1), amount!(5)),
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(2),
+                amount: amount!(5),
+            }),
+            dispute(tx(1)),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+        assert_eq!(handler.statistics().rejected, 0);
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(-5));
+        assert_eq!(accounts[0].held, amount!(5));
+        assert_eq!(accounts[0].total(), amount!(0));
+    }
+
+    #[test]
+    fn deposit_into_overdrawn_account() {
+        let mut handler = TransactionHandler::new();
+
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+        let withdrawal = |transaction, amount| {
+            Transaction::Withdrawal(MonetaryTransactionRecord {
+                client: 0,
+                transaction,
+                amount,
+            })
+        };
+        handler.apply(withdrawal(tx(2), amount!(5))).unwrap();
+        handler.apply(dispute(tx(1))).unwrap();
+
+        // a partial deposit is accepted although the deficit remains
+        handler.apply(deposit(tx(3), amount!(2))).unwrap();
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(-3));
+        assert_eq!(accounts[0].held, amount!(5));
+
+        // withdrawals must not deepen the deficit
+        handler.apply(withdrawal(tx(4), amount!(1))).unwrap_err();
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(-3));
+    }
+
+    #[test]
+    fn diff_handlers() {
+        let mut handler = TransactionHandler::new();
+        handler.apply(deposit(tx(1), amount!(5))).unwrap();
+        handler
+            .apply(Transaction::Deposit(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(2),
+                amount: amount!(3),
+            }))
+            .unwrap();
+
+        let mut other = handler.clone();
+        assert!(handler.diff(&other).is_empty());
+
+        other.apply(dispute(tx(1))).unwrap();
+        other
+            .apply(Transaction::Chargeback(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }))
+            .unwrap();
+
+        let diff = handler.diff(&other);
+        assert_eq!(
+            diff,
+            vec![AccountDiff {
+                client: 0,
+                own: Some(Account {
+                    client: 0,
+                    available: amount!(5),
+                    held: amount!(0),
+                    locked: false,
+                }),
+                other: Some(Account {
+                    client: 0,
+                    available: amount!(0),
+                    held: amount!(0),
+                    locked: true,
+                }),
+            }]
+        );
+
+        // an account missing on one side is a difference as well
+        let diff = TransactionHandler::new().diff(&handler);
+        assert_eq!(diff.len(), 2);
+        assert!(diff.iter().all(|entry| entry.own.is_none()));
+    }
+
+    fn handler_with_granularity() -> TransactionHandler {
+        let mut handler = TransactionHandler::with_config(HandlerConfig {
+            release_granularity: Some(Granularity::new(amount!(0.01)).unwrap()),
+            ..HandlerConfig::default()
+        });
+        handler.apply(deposit(tx(1), amount!(1.005))).unwrap();
+        handler.apply(dispute(tx(1))).unwrap();
+        handler
+    }
+
+    fn resolve(transaction: TransactionId) -> Transaction {
+        Transaction::Resolve(DisputedTransactionRecord {
+            client: 0,
+            transaction,
+        })
+    }
+
+    #[test]
+    fn rejected_release_keeps_dispute_open() {
+        let mut handler = handler_with_granularity();
+
+        handler.apply(resolve(tx(1))).unwrap_err();
+        assert_eq!(
+            handler.transaction_audit()[0].status,
+            Some(DisputeStatus::Disputed)
+        );
+
+        // the dispute can still be charged back, nothing is stranded
+        handler
+            .apply(Transaction::Chargeback(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }))
+            .unwrap();
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].total(), amount!(0));
+        assert_eq!(handler.reconcile().computed_total, amount!(0));
+    }
+}
+
+#[cfg(all(test, feature = "float-amount"))]
+mod float_tests {
+    use super::*;
+    use crate::csv_parser::iter_transactions;
+    use crate::test_support::tx;
+    use crate::types::*;
+
+    /// Floating point amounts are not exact, so balances are only compared within this tolerance
+    const TOLERANCE: Amount = 1e-9;
+
+    fn single_account(handler: &mut TransactionHandler) -> Account {
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts.len(), 1);
+        accounts[0].clone()
+    }
+
+    #[test]
+    fn deposit_then_withdraw() {
+        let mut handler = TransactionHandler::new();
+
+        let source = br#"
+type, client, tx, amount
+deposit, 0, 0, 0.1
+deposit, 0, 1, 0.2
+withdrawal, 0, 2, 0.3
+"#;
+        handler.handle_transactions(iter_transactions(&source[..]));
+
+        let account = single_account(&mut handler);
+        assert!(account.available.abs() < TOLERANCE);
+        assert!(account.held.abs() < TOLERANCE);
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn deposit_dispute_charge_back() {
+        let mut handler = TransactionHandler::new();
+
+        let transactions = vec![
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(0),
+                amount: 1.1,
+            }),
+            Transaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: 2.2,
+            }),
+            Transaction::Dispute(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            }),
+        ];
+        handler.handle_transactions(transactions.into_iter().map(Ok));
+
+        let account = single_account(&mut handler);
+        assert!((account.available - 1.1).abs() < TOLERANCE);
+        assert!((account.held - 2.2).abs() < TOLERANCE);
+        assert!((account.total() - 3.3).abs() < TOLERANCE);
+
+        handler.handle_transactions(
+            vec![Transaction::Chargeback(DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            })]
+            .into_iter()
+            .map(Ok),
+        );
+
+        let account = single_account(&mut handler);
+        assert!((account.available - 1.1).abs() < TOLERANCE);
+        assert!(account.held.abs() < TOLERANCE);
+        assert!(account.locked);
+    }
+}
+
+#[cfg(all(test, feature = "string-tx-id"))]
+mod string_id_tests {
+    use super::*;
+    use crate::csv_parser::iter_transactions;
+    use crate::test_support::amount;
+
+    #[test]
+    fn dispute_uuid_keyed_deposit() {
+        let source = br#"
+type, client, tx, amount
+deposit, 1, 3f2b8c1e-7d4a-4e9b-a1c6-0f5e2d7b9a44, 5.0
+deposit, 1, 9c0d6e2a-1b3f-4a5c-8d7e-6f1a2b3c4d5e, 2.0
+dispute, 1, 3f2b8c1e-7d4a-4e9b-a1c6-0f5e2d7b9a44,
+"#;
+        let mut handler = TransactionHandler::new();
+        handler.handle_transactions(iter_transactions(&source[..]));
+        assert_eq!(handler.statistics().rejected, 0);
+
+        let accounts: Vec<_> = handler.into_iter().collect();
+        assert_eq!(accounts[0].available, amount!(2));
+        assert_eq!(accounts[0].held, amount!(5));
+    }
+
+    #[test]
+    fn overlong_id() {
+        let id = "x".repeat(crate::types::STRING_ID_CAPACITY + 1);
+        let source = format!("type, client, tx, amount\ndeposit, 1, {}, 5.0\n", id);
+        let mut handler = TransactionHandler::new();
+        handler.handle_transactions(iter_transactions(source.as_bytes()));
+        assert_eq!(handler.statistics().rejected, 1);
+    }
 }
@@ -1,8 +1,10 @@
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+use crate::error::TransactionError;
 use crate::types::{
-    Amount, ClientId, DisputableTransaction, DisputedTransactionRecord, MonetaryTransactionRecord,
+    Amount, ClientId, DisputableTransaction, DisputeOutcome, DisputeStatus,
+    DisputedTransactionRecord, MonetaryTransactionRecord, StoreHasher, StoredTransaction,
     TransactionId,
 };
 
@@ -18,11 +20,22 @@ pub enum UndisputeOutcome {
 /// Store transactions for later possibility to dispute
 pub trait TransactionStore {
     /// Add a transaction to the store
-    /// No transaction with the same ID may have been added before.
+    /// No transaction with the same ID may have been added before, no matter its dispute state.
     fn add_transaction(&mut self, transaction: DisputableTransaction) -> Result<()>;
 
+    /// Remember the ID of a withdrawal to tell it apart from unknown IDs (it is not disputable)
+    fn add_withdrawal(&mut self, transaction: TransactionId);
+
+    /// Whether the ID belongs to a withdrawal that has been added before (either with
+    /// `add_withdrawal` or as a disputable transaction)
+    fn is_withdrawal(&self, transaction: TransactionId) -> bool;
+
+    /// Look up a stored transaction without changing its state
+    fn transaction(&self, transaction: TransactionId) -> Option<DisputableTransaction>;
+
     /// Dispute a transaction
     /// The transaction must have been added and it may not have gone through chargeback.
+    /// A resolved transaction can be disputed again.
     fn dispute_transaction(
         &mut self,
         transaction: &DisputedTransactionRecord,
@@ -35,33 +48,150 @@ pub trait TransactionStore {
         transaction: &DisputedTransactionRecord,
         outcome: UndisputeOutcome,
     ) -> Result<DisputableTransaction>;
+
+    /// List all transactions that have ever been disputed (ordered by transaction ID)
+    fn dispute_report(&self) -> Vec<DisputeOutcome>;
+
+    /// List all retained transactions with their dispute status (ordered by transaction ID)
+    fn stored_transactions(&self) -> Vec<StoredTransaction>;
+
+    /// Number of transactions retained for potential disputes
+    fn len(&self) -> usize;
+
+    /// Capture everything stored about the transaction ID (e.g. to undo a change later)
+    fn snapshot(&self, transaction: TransactionId) -> TransactionSnapshot;
+
+    /// Put everything stored about the transaction ID back into the captured state
+    /// Fails for stores that cannot take back changes they have already persisted.
+    fn restore(&mut self, transaction: TransactionId, snapshot: TransactionSnapshot) -> Result<()>;
+
+    /// Make all changes so far durable (nothing to do for stores that only live in memory)
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether no transaction is retained at all
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 enum DisputeState {
     NotDisputed,
     Disputed,
+    Resolved,
     ChargebackOcurred,
 }
 
-#[derive(Debug, PartialEq)]
+impl DisputeState {
+    /// Map to the public status, `None` if the transaction was never disputed
+    fn status(&self) -> Option<DisputeStatus> {
+        match self {
+            DisputeState::NotDisputed => None,
+            DisputeState::Disputed => Some(DisputeStatus::Disputed),
+            DisputeState::Resolved => Some(DisputeStatus::Resolved),
+            DisputeState::ChargebackOcurred => Some(DisputeStatus::ChargedBack),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 struct DisputableTransactionData {
     client: ClientId,
     amount: Amount,
     state: DisputeState,
+    withdrawal: bool,
+}
+
+impl DisputableTransactionData {
+    /// Recreate the transaction with the ID `transaction` from the stored data
+    fn to_transaction(&self, transaction: TransactionId) -> DisputableTransaction {
+        let record = MonetaryTransactionRecord {
+            client: self.client,
+            transaction,
+            amount: self.amount,
+        };
+        if self.withdrawal {
+            DisputableTransaction::Withdrawal(record)
+        } else {
+            DisputableTransaction::Deposit(record)
+        }
+    }
+}
+
+/// The complete state of a single transaction ID to restore it later
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionSnapshot {
+    data: Option<DisputableTransactionData>,
+    withdrawal: bool,
 }
 
 /// A simple RAM-backed transaction store using a standard Rust `HashMap`
+#[derive(Clone)]
 pub struct HashMapTransactionStore {
-    data_store: HashMap<TransactionId, DisputableTransactionData>,
+    data_store: HashMap<TransactionId, DisputableTransactionData, StoreHasher>,
+
+    /// IDs of the withdrawals added with `add_withdrawal` (disputable ones are in `data_store`)
+    withdrawals: HashSet<TransactionId>,
+
+    /// Stop storing new transactions and withdrawal IDs once this many are stored
+    limit: Option<usize>,
+    limit_reached: bool,
 }
 
 impl HashMapTransactionStore {
     pub fn new() -> Self {
         Self {
-            data_store: HashMap::new(),
+            data_store: HashMap::default(),
+            withdrawals: HashSet::new(),
+            limit: None,
+            limit_reached: false,
         }
     }
+
+    /// Create a store that keeps at most `limit` transactions to bound the memory usage
+    /// Further transactions are silently not stored (and can therefore not be disputed). Withdrawal
+    /// IDs count against the same limit.
+    /// Duplicate IDs are only detected among the stored transactions, so a transaction beyond the
+    /// limit can reuse the ID of another one beyond the limit without an error.
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            data_store: HashMap::default(),
+            withdrawals: HashSet::new(),
+            limit: Some(limit),
+            limit_reached: false,
+        }
+    }
+
+    /// The error for a reference to a transaction that is not stored (a withdrawal or unknown ID)
+    fn unknown_transaction(&self, transaction: TransactionId) -> anyhow::Error {
+        if self.withdrawals.contains(&transaction) {
+            TransactionError::NotDisputable { transaction }.into()
+        } else {
+            TransactionError::TransactionNotFound { transaction }.into()
+        }
+    }
+
+    /// Whether the limit leaves no room to store `transaction` (warns when it is first hit)
+    fn at_limit(&mut self, transaction: TransactionId) -> bool {
+        let stored = self.data_store.len() + self.withdrawals.len();
+        if self.limit.is_none_or(|limit| stored < limit) {
+            return false;
+        }
+
+        if self.limit_reached {
+            debug!("Transaction not stored (tx = {})", transaction);
+        } else {
+            warn!(
+                "Transaction store limit reached, further transactions will not be \
+                 disputable (tx = {})",
+                transaction
+            );
+            self.limit_reached = true;
+        }
+        true
+    }
 }
 
 impl Default for HashMapTransactionStore {
@@ -72,28 +202,50 @@ impl Default for HashMapTransactionStore {
 
 impl TransactionStore for HashMapTransactionStore {
     fn add_transaction(&mut self, transaction: DisputableTransaction) -> Result<()> {
-        match transaction {
-            DisputableTransaction::Deposit(record) => {
-                if self.data_store.contains_key(&record.transaction) {
-                    return Err(anyhow!(
-                        "Transaction already present (tx = {})",
-                        record.transaction
-                    ));
-                }
-
-                self.data_store.insert(
-                    record.transaction,
-                    DisputableTransactionData {
-                        client: record.client,
-                        amount: record.amount,
-                        state: DisputeState::NotDisputed,
-                    },
-                );
-            }
+        let withdrawal = matches!(transaction, DisputableTransaction::Withdrawal(_));
+        let record = transaction.record();
+        if self.data_store.contains_key(&record.transaction) {
+            return Err(anyhow!(
+                "Transaction already present (tx = {})",
+                record.transaction
+            ));
+        }
+        if self.at_limit(record.transaction) {
+            return Ok(());
         }
+
+        self.data_store.insert(
+            record.transaction,
+            DisputableTransactionData {
+                client: record.client,
+                amount: record.amount,
+                state: DisputeState::NotDisputed,
+                withdrawal,
+            },
+        );
         Ok(())
     }
 
+    fn add_withdrawal(&mut self, transaction: TransactionId) {
+        if !self.at_limit(transaction) {
+            self.withdrawals.insert(transaction);
+        }
+    }
+
+    fn is_withdrawal(&self, transaction: TransactionId) -> bool {
+        self.withdrawals.contains(&transaction)
+            || self
+                .data_store
+                .get(&transaction)
+                .is_some_and(|data| data.withdrawal)
+    }
+
+    fn transaction(&self, transaction: TransactionId) -> Option<DisputableTransaction> {
+        self.data_store
+            .get(&transaction)
+            .map(|data| data.to_transaction(transaction))
+    }
+
     fn dispute_transaction(
         &mut self,
         transaction: &DisputedTransactionRecord,
@@ -106,7 +258,7 @@ impl TransactionStore for HashMapTransactionStore {
                 ));
             }
 
-            if data.state != DisputeState::NotDisputed {
+            if data.state != DisputeState::NotDisputed && data.state != DisputeState::Resolved {
                 return Err(anyhow!(
                     "Transaction already disputed (tx = {})",
                     transaction.transaction
@@ -114,17 +266,9 @@ impl TransactionStore for HashMapTransactionStore {
             }
 
             data.state = DisputeState::Disputed;
-
-            Ok(DisputableTransaction::Deposit(MonetaryTransactionRecord {
-                client: data.client,
-                transaction: transaction.transaction,
-                amount: data.amount,
-            }))
+            Ok(data.to_transaction(transaction.transaction))
         } else {
-            Err(anyhow!(
-                "Transaction not found for dispute (tx = {})",
-                transaction.transaction
-            ))
+            Err(self.unknown_transaction(transaction.transaction))
         }
     }
 
@@ -141,39 +285,103 @@ impl TransactionStore for HashMapTransactionStore {
                 ));
             }
 
-            if data.state != DisputeState::Disputed {
-                return Err(anyhow!(
-                    "Transaction not yet disputed (tx = {})",
-                    transaction.transaction
-                ));
+            match data.state.status() {
+                Some(DisputeStatus::Disputed) => {}
+                Some(status) => {
+                    return Err(TransactionError::NotCurrentlyDisputed {
+                        transaction: transaction.transaction,
+                        status,
+                    }
+                    .into())
+                }
+                None => {
+                    return Err(anyhow!(
+                        "Transaction not yet disputed (tx = {})",
+                        transaction.transaction
+                    ))
+                }
             }
 
             match outcome {
-                UndisputeOutcome::Resolve => data.state = DisputeState::NotDisputed,
+                UndisputeOutcome::Resolve => data.state = DisputeState::Resolved,
                 UndisputeOutcome::Chargeback => data.state = DisputeState::ChargebackOcurred,
             }
+            Ok(data.to_transaction(transaction.transaction))
+        } else {
+            Err(self.unknown_transaction(transaction.transaction))
+        }
+    }
 
-            Ok(DisputableTransaction::Deposit(MonetaryTransactionRecord {
+    fn dispute_report(&self) -> Vec<DisputeOutcome> {
+        let mut report: Vec<_> = self
+            .data_store
+            .iter()
+            .filter_map(|(transaction, data)| {
+                data.state.status().map(|status| DisputeOutcome {
+                    client: data.client,
+                    transaction: *transaction,
+                    status,
+                })
+            })
+            .collect();
+        report.sort_by_key(|outcome| outcome.transaction);
+        report
+    }
+
+    fn stored_transactions(&self) -> Vec<StoredTransaction> {
+        let mut transactions: Vec<_> = self
+            .data_store
+            .iter()
+            .map(|(transaction, data)| StoredTransaction {
+                transaction: *transaction,
                 client: data.client,
-                transaction: transaction.transaction,
                 amount: data.amount,
-            }))
+                status: data.state.status(),
+            })
+            .collect();
+        transactions.sort_by_key(|stored| stored.transaction);
+        transactions
+    }
+
+    fn snapshot(&self, transaction: TransactionId) -> TransactionSnapshot {
+        TransactionSnapshot {
+            data: self.data_store.get(&transaction).cloned(),
+            withdrawal: self.withdrawals.contains(&transaction),
+        }
+    }
+
+    fn restore(&mut self, transaction: TransactionId, snapshot: TransactionSnapshot) -> Result<()> {
+        match snapshot.data {
+            Some(data) => self.data_store.insert(transaction, data),
+            None => self.data_store.remove(&transaction),
+        };
+        if snapshot.withdrawal {
+            self.withdrawals.insert(transaction);
         } else {
-            Err(anyhow!(
-                "Transaction not found for undispute (tx = {})",
-                transaction.transaction
-            ))
+            self.withdrawals.remove(&transaction);
+        }
+
+        // Warn again if the limit is hit anew after undoing a change
+        let stored = self.data_store.len() + self.withdrawals.len();
+        if self.limit.is_some_and(|limit| stored < limit) {
+            self.limit_reached = false;
         }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.data_store.len()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::tx;
 
     use crate::types::*;
 
-    use rust_decimal_macros::dec;
+    use crate::test_support::amount;
 
     #[test]
     fn add_dispute_resolve() {
@@ -181,22 +389,22 @@ mod tests {
 
         let deposit = DisputableTransaction::Deposit(MonetaryTransactionRecord {
             client: 0,
-            transaction: 0,
-            amount: dec!(1.0),
+            transaction: tx(0),
+            amount: amount!(1.0),
         });
-        store.add_transaction(deposit).unwrap();
+        store.add_transaction(deposit.clone()).unwrap();
 
         let dispute = DisputedTransactionRecord {
             client: 0,
-            transaction: 0,
+            transaction: tx(0),
         };
-        let DisputableTransaction::Deposit(record) = store.dispute_transaction(&dispute).unwrap();
-        assert_eq!(record.amount, dec!(1.0));
-
-        let DisputableTransaction::Deposit(record) = store
-            .undispute_transaction(&dispute, UndisputeOutcome::Resolve)
-            .unwrap();
-        assert_eq!(record.amount, dec!(1.0));
+        assert_eq!(store.dispute_transaction(&dispute).unwrap(), deposit);
+        assert_eq!(
+            store
+                .undispute_transaction(&dispute, UndisputeOutcome::Resolve)
+                .unwrap(),
+            deposit
+        );
 
         // after resolve, the transaction can be disputed again
         store.dispute_transaction(&dispute).unwrap();
@@ -208,35 +416,61 @@ mod tests {
 
         let deposit = DisputableTransaction::Deposit(MonetaryTransactionRecord {
             client: 0,
-            transaction: 0,
-            amount: dec!(1.0),
+            transaction: tx(0),
+            amount: amount!(1.0),
         });
-        store.add_transaction(deposit).unwrap();
+        store.add_transaction(deposit.clone()).unwrap();
 
         let dispute = DisputedTransactionRecord {
             client: 0,
-            transaction: 0,
+            transaction: tx(0),
         };
-        let DisputableTransaction::Deposit(record) = store.dispute_transaction(&dispute).unwrap();
-        assert_eq!(record.amount, dec!(1.0));
-
-        let DisputableTransaction::Deposit(record) = store
-            .undispute_transaction(&dispute, UndisputeOutcome::Chargeback)
-            .unwrap();
-        assert_eq!(record.amount, dec!(1.0));
+        assert_eq!(store.dispute_transaction(&dispute).unwrap(), deposit);
+        assert_eq!(
+            store
+                .undispute_transaction(&dispute, UndisputeOutcome::Chargeback)
+                .unwrap(),
+            deposit
+        );
 
         // after chargeback, the transaction cannot be disputed again
         store.dispute_transaction(&dispute).unwrap_err();
     }
 
+    #[test]
+    fn disputable_withdrawal() {
+        let mut store = HashMapTransactionStore::new();
+
+        let withdrawal = DisputableTransaction::Withdrawal(MonetaryTransactionRecord {
+            client: 0,
+            transaction: tx(0),
+            amount: amount!(1.0),
+        });
+        store.add_transaction(withdrawal.clone()).unwrap();
+        assert!(store.is_withdrawal(tx(0)));
+        assert_eq!(store.transaction(tx(0)), Some(withdrawal.clone()));
+
+        let dispute = DisputedTransactionRecord {
+            client: 0,
+            transaction: tx(0),
+        };
+        assert_eq!(store.dispute_transaction(&dispute).unwrap(), withdrawal);
+        assert_eq!(
+            store
+                .undispute_transaction(&dispute, UndisputeOutcome::Chargeback)
+                .unwrap(),
+            withdrawal
+        );
+    }
+
     #[test]
     fn add_twice() {
         let mut store = HashMapTransactionStore::new();
 
         let deposit = DisputableTransaction::Deposit(MonetaryTransactionRecord {
             client: 0,
-            transaction: 0,
-            amount: dec!(1.0),
+            transaction: tx(0),
+            amount: amount!(1.0),
         });
         store.add_transaction(deposit.clone()).unwrap();
         store.add_transaction(deposit).unwrap_err();
@@ -248,7 +482,7 @@ mod tests {
 
         let dispute = DisputedTransactionRecord {
             client: 0,
-            transaction: 0,
+            transaction: tx(0),
         };
         store.dispute_transaction(&dispute).unwrap_err();
     }
@@ -259,7 +493,7 @@ mod tests {
 
         let dispute = DisputedTransactionRecord {
             client: 0,
-            transaction: 0,
+            transaction: tx(0),
         };
         store
             .undispute_transaction(&dispute, UndisputeOutcome::Resolve)
@@ -272,17 +506,191 @@ mod tests {
 
         let deposit = DisputableTransaction::Deposit(MonetaryTransactionRecord {
             client: 0,
-            transaction: 0,
-            amount: dec!(1.0),
+            transaction: tx(0),
+            amount: amount!(1.0),
         });
         store.add_transaction(deposit.clone()).unwrap();
 
         let dispute = DisputedTransactionRecord {
             client: 0,
-            transaction: 0,
+            transaction: tx(0),
         };
         store
             .undispute_transaction(&dispute, UndisputeOutcome::Resolve)
             .unwrap_err();
     }
+
+    #[test]
+    fn dispute_report() {
+        let mut store = HashMapTransactionStore::new();
+
+        for transaction in 0..4 {
+            let deposit = DisputableTransaction::Deposit(MonetaryTransactionRecord {
+                client: 7,
+                transaction: tx(transaction),
+                amount: amount!(1.0),
+            });
+            store.add_transaction(deposit).unwrap();
+        }
+
+        let dispute = |transaction| DisputedTransactionRecord {
+            client: 7,
+            transaction,
+        };
+        store.dispute_transaction(&dispute(tx(1))).unwrap();
+        store.dispute_transaction(&dispute(tx(2))).unwrap();
+        store
+            .undispute_transaction(&dispute(tx(2)), UndisputeOutcome::Resolve)
+            .unwrap();
+        store.dispute_transaction(&dispute(tx(3))).unwrap();
+        store
+            .undispute_transaction(&dispute(tx(3)), UndisputeOutcome::Chargeback)
+            .unwrap();
+
+        let outcome = |transaction, status| DisputeOutcome {
+            client: 7,
+            transaction,
+            status,
+        };
+        assert_eq!(
+            store.dispute_report(),
+            vec![
+                outcome(tx(1), DisputeStatus::Disputed),
+                outcome(tx(2), DisputeStatus::Resolved),
+                outcome(tx(3), DisputeStatus::ChargedBack),
+            ]
+        );
+
+        let statuses: Vec<_> = store
+            .stored_transactions()
+            .into_iter()
+            .map(|stored| (stored.transaction, stored.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (tx(0), None),
+                (tx(1), Some(DisputeStatus::Disputed)),
+                (tx(2), Some(DisputeStatus::Resolved)),
+                (tx(3), Some(DisputeStatus::ChargedBack)),
+            ]
+        );
+    }
+
+    #[test]
+    fn add_beyond_limit() {
+        let mut store = HashMapTransactionStore::with_limit(2);
+
+        for transaction in 0..4 {
+            let deposit = DisputableTransaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(transaction),
+                amount: amount!(1.0),
+            });
+            store.add_transaction(deposit).unwrap();
+        }
+
+        let dispute = |transaction| DisputedTransactionRecord {
+            client: 0,
+            transaction,
+        };
+        store.dispute_transaction(&dispute(tx(1))).unwrap();
+        store.dispute_transaction(&dispute(tx(2))).unwrap_err();
+        store.dispute_transaction(&dispute(tx(3))).unwrap_err();
+    }
+
+    #[test]
+    fn duplicate_beyond_limit() {
+        let mut store = HashMapTransactionStore::with_limit(1);
+        let deposit = |transaction| {
+            DisputableTransaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction,
+                amount: amount!(1.0),
+            })
+        };
+
+        store.add_transaction(deposit(tx(0))).unwrap();
+        store.add_transaction(deposit(tx(1))).unwrap();
+
+        // stored IDs are still checked, IDs beyond the limit are not remembered at all
+        store.add_transaction(deposit(tx(0))).unwrap_err();
+        store.add_transaction(deposit(tx(1))).unwrap();
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn withdrawals_count_against_limit() {
+        let mut store = HashMapTransactionStore::with_limit(2);
+
+        store.add_withdrawal(tx(0));
+        store
+            .add_transaction(DisputableTransaction::Deposit(MonetaryTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+                amount: amount!(1.0),
+            }))
+            .unwrap();
+        store.add_withdrawal(tx(2));
+
+        assert!(store.is_withdrawal(tx(0)));
+        assert!(!store.is_withdrawal(tx(2)));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn len() {
+        let mut store = HashMapTransactionStore::new();
+        assert!(store.is_empty());
+
+        for transaction in 0..3 {
+            store
+                .add_transaction(DisputableTransaction::Deposit(MonetaryTransactionRecord {
+                    client: 0,
+                    transaction: tx(transaction),
+                    amount: amount!(1.0),
+                }))
+                .unwrap();
+        }
+        assert_eq!(store.len(), 3);
+
+        store
+            .dispute_transaction(&DisputedTransactionRecord {
+                client: 0,
+                transaction: tx(1),
+            })
+            .unwrap();
+        assert_eq!(store.len(), 3);
+        assert!(!store.is_empty());
+    }
+
+    #[test]
+    fn undispute_withdrawal_or_unknown() {
+        let mut store = HashMapTransactionStore::new();
+        store.add_withdrawal(tx(1));
+
+        let withdrawal = DisputedTransactionRecord {
+            client: 0,
+            transaction: tx(1),
+        };
+        let error = store
+            .undispute_transaction(&withdrawal, UndisputeOutcome::Resolve)
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&TransactionError::NotDisputable { transaction: tx(1) })
+        );
+
+        let unknown = DisputedTransactionRecord {
+            client: 0,
+            transaction: tx(2),
+        };
+        let error = store
+            .undispute_transaction(&unknown, UndisputeOutcome::Chargeback)
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref(),
+            Some(&TransactionError::TransactionNotFound { transaction: tx(2) })
+        );
+    }
 }
@@ -1,10 +1,117 @@
-use rust_decimal::Decimal;
+use crate::error::TransactionError;
 
 pub type ClientId = u16;
 
+/// Transaction IDs are numbers unless the `string-tx-id` feature allows any text (e.g. UUIDs)
+#[cfg(not(feature = "string-tx-id"))]
 pub type TransactionId = u32;
 
-pub type Amount = Decimal;
+#[cfg(feature = "string-tx-id")]
+pub type TransactionId = StringId;
+
+/// Maximum length of a `StringId` in bytes
+#[cfg(feature = "string-tx-id")]
+pub const STRING_ID_CAPACITY: usize = 64;
+
+/// A transaction ID given as text, stored inline with a fixed size of 65 bytes so that it never
+/// allocates
+#[cfg(feature = "string-tx-id")]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StringId {
+    length: u8,
+    bytes: [u8; STRING_ID_CAPACITY],
+}
+
+#[cfg(feature = "string-tx-id")]
+impl StringId {
+    pub fn as_str(&self) -> &str {
+        // only ever constructed from a `str`, so the bytes are valid UTF-8
+        std::str::from_utf8(&self.bytes[..self.length as usize]).unwrap_or_default()
+    }
+}
+
+/// Ordered like the text itself (not by length first)
+#[cfg(feature = "string-tx-id")]
+impl Ord for StringId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+#[cfg(feature = "string-tx-id")]
+impl PartialOrd for StringId {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "string-tx-id")]
+impl std::str::FromStr for StringId {
+    type Err = anyhow::Error;
+
+    fn from_str(id: &str) -> anyhow::Result<Self> {
+        if id.is_empty() {
+            return Err(anyhow::anyhow!("Empty transaction ID"));
+        }
+        if id.len() > STRING_ID_CAPACITY {
+            return Err(anyhow::anyhow!(
+                "Transaction ID is longer than {} bytes",
+                STRING_ID_CAPACITY
+            ));
+        }
+
+        let mut bytes = [0; STRING_ID_CAPACITY];
+        bytes[..id.len()].copy_from_slice(id.as_bytes());
+        Ok(Self {
+            length: id.len() as u8,
+            bytes,
+        })
+    }
+}
+
+#[cfg(feature = "string-tx-id")]
+impl std::fmt::Display for StringId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "string-tx-id")]
+impl std::fmt::Debug for StringId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[cfg(feature = "string-tx-id")]
+impl<'de> serde::Deserialize<'de> for StringId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = <std::borrow::Cow<str>>::deserialize(deserializer)?;
+        id.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "string-tx-id")]
+impl serde::Serialize for StringId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Monetary amounts are exact decimals unless the `float-amount` feature trades exactness for speed
+#[cfg(not(feature = "float-amount"))]
+pub type Amount = rust_decimal::Decimal;
+
+#[cfg(feature = "float-amount")]
+pub type Amount = f64;
+
+/// Hasher of the stores, randomly seeded unless the `deterministic` feature fixes the keys
+#[cfg(not(feature = "deterministic"))]
+pub(crate) type StoreHasher = std::collections::hash_map::RandomState;
+
+#[cfg(feature = "deterministic")]
+pub(crate) type StoreHasher =
+    std::hash::BuildHasherDefault<std::collections::hash_map::DefaultHasher>;
 
 /// Represents money flowing towards or from a client account
 #[derive(Debug, Clone, PartialEq)]
@@ -14,6 +121,133 @@ pub struct MonetaryTransactionRecord {
     pub amount: Amount,
 }
 
+/// Number of decimal places of the minor unit
+const MINOR_UNIT_SCALE: u32 = 4;
+
+/// What to do with amounts that have a fraction of a minor unit (more than 4 decimal places)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SubMinorUnitPolicy {
+    /// Keep the full precision
+    #[default]
+    Keep,
+
+    /// Round to the nearest minor unit (half away from zero)
+    Round,
+
+    /// Cut off the fraction of a minor unit
+    Truncate,
+
+    /// Fail the transaction with `TransactionError::ExcessiveScale`
+    Reject,
+}
+
+/// Round (or truncate) the amount to whole minor units
+#[cfg(not(feature = "float-amount"))]
+fn to_whole_minor_units(amount: Amount, truncate: bool) -> Amount {
+    use rust_decimal::RoundingStrategy;
+
+    let strategy = if truncate {
+        RoundingStrategy::ToZero
+    } else {
+        RoundingStrategy::MidpointAwayFromZero
+    };
+    amount.round_dp_with_strategy(MINOR_UNIT_SCALE, strategy)
+}
+
+/// Round (or truncate) the amount to whole minor units
+#[cfg(feature = "float-amount")]
+fn to_whole_minor_units(amount: Amount, truncate: bool) -> Amount {
+    let factor = 10_f64.powi(MINOR_UNIT_SCALE as i32);
+    let scaled = amount * factor;
+    let whole = if truncate {
+        scaled.trunc()
+    } else {
+        scaled.round()
+    };
+    whole / factor
+}
+
+impl SubMinorUnitPolicy {
+    /// Apply the policy to the amount of `transaction`
+    pub fn apply(
+        self,
+        amount: Amount,
+        transaction: TransactionId,
+    ) -> Result<Amount, TransactionError> {
+        match self {
+            SubMinorUnitPolicy::Keep => Ok(amount),
+            SubMinorUnitPolicy::Round => Ok(to_whole_minor_units(amount, false)),
+            SubMinorUnitPolicy::Truncate => Ok(to_whole_minor_units(amount, true)),
+            SubMinorUnitPolicy::Reject => {
+                if to_whole_minor_units(amount, true) != amount {
+                    return Err(TransactionError::ExcessiveScale {
+                        transaction,
+                        max_scale: MINOR_UNIT_SCALE,
+                    });
+                }
+                Ok(amount)
+            }
+        }
+    }
+}
+
+/// Rules for the amounts of deposits and withdrawals, the default accepts everything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationConfig {
+    /// Reject negative amounts
+    pub reject_negative: bool,
+
+    /// Reject amounts with more decimal places (trailing zeros do not count)
+    pub max_scale: Option<u32>,
+
+    /// Reject amounts larger than this
+    pub max_amount: Option<Amount>,
+}
+
+/// Number of decimal places of an amount, ignoring trailing zeros
+#[cfg(not(feature = "float-amount"))]
+fn amount_scale(amount: Amount) -> u32 {
+    amount.normalize().scale()
+}
+
+/// Number of decimal places of an amount (in its shortest representation)
+#[cfg(feature = "float-amount")]
+fn amount_scale(amount: Amount) -> u32 {
+    let text = amount.to_string();
+    text.find('.')
+        .map_or(0, |point| (text.len() - point - 1) as u32)
+}
+
+impl MonetaryTransactionRecord {
+    /// Check the amount against the given rules
+    pub fn validate(&self, config: &ValidationConfig) -> Result<(), TransactionError> {
+        if config.reject_negative && self.amount < Amount::default() {
+            return Err(TransactionError::NegativeAmount {
+                transaction: self.transaction,
+            });
+        }
+
+        if let Some(max_scale) = config.max_scale {
+            if amount_scale(self.amount) > max_scale {
+                return Err(TransactionError::ExcessiveScale {
+                    transaction: self.transaction,
+                    max_scale,
+                });
+            }
+        }
+
+        if let Some(max_amount) = config.max_amount {
+            if self.amount > max_amount {
+                return Err(TransactionError::AmountOutOfRange {
+                    transaction: self.transaction,
+                    max_amount,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
 /// References a `MonetaryTransactionRecord` for use in dispute claim handling
 #[derive(Debug, Clone, PartialEq)]
 pub struct DisputedTransactionRecord {
@@ -21,6 +255,31 @@ pub struct DisputedTransactionRecord {
     pub transaction: TransactionId,
 }
 
+/// The kinds of transactions without any data (e.g. to enable or disable them)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+/// Use the same names as in the input CSV
+impl std::fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+        };
+        f.write_str(name)
+    }
+}
+
 /// A transaction that can occur in the processor's input
 #[derive(Debug, Clone, PartialEq)]
 pub enum Transaction {
@@ -31,12 +290,146 @@ pub enum Transaction {
     Chargeback(DisputedTransactionRecord),
 }
 
+/// Rules for the amounts of `Transaction::deposit` and `Transaction::withdrawal`
+const CONSTRUCTOR_VALIDATION: ValidationConfig = ValidationConfig {
+    reject_negative: true,
+    max_scale: Some(4),
+    max_amount: None,
+};
+
+/// The record for `Transaction::deposit` and `Transaction::withdrawal`, rejecting zero amounts with
+/// `TransactionError::ZeroAmount` just like the parser does
+fn validated_record(
+    client: ClientId,
+    transaction: TransactionId,
+    amount: Amount,
+) -> Result<MonetaryTransactionRecord, TransactionError> {
+    let record = MonetaryTransactionRecord {
+        client,
+        transaction,
+        amount,
+    };
+    record.validate(&CONSTRUCTOR_VALIDATION)?;
+    if record.amount == Amount::default() {
+        return Err(TransactionError::ZeroAmount { transaction });
+    }
+    Ok(record)
+}
+
+impl Transaction {
+    /// Create a deposit, the amount must be positive and have at most 4 decimal places
+    pub fn deposit(
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Self, TransactionError> {
+        validated_record(client, transaction, amount).map(Transaction::Deposit)
+    }
+
+    /// Create a withdrawal, the amount must be positive and have at most 4 decimal places
+    pub fn withdrawal(
+        client: ClientId,
+        transaction: TransactionId,
+        amount: Amount,
+    ) -> Result<Self, TransactionError> {
+        validated_record(client, transaction, amount).map(Transaction::Withdrawal)
+    }
+
+    /// The client this transaction belongs to
+    pub fn client(&self) -> ClientId {
+        match self {
+            Transaction::Deposit(record) | Transaction::Withdrawal(record) => record.client,
+            Transaction::Dispute(record)
+            | Transaction::Resolve(record)
+            | Transaction::Chargeback(record) => record.client,
+        }
+    }
+
+    /// The ID of this transaction (or of the referenced one for disputes, resolves, chargebacks)
+    pub fn transaction_id(&self) -> TransactionId {
+        match self {
+            Transaction::Deposit(record) | Transaction::Withdrawal(record) => record.transaction,
+            Transaction::Dispute(record)
+            | Transaction::Resolve(record)
+            | Transaction::Chargeback(record) => record.transaction,
+        }
+    }
+
+    /// The kind of this transaction
+    pub fn transaction_type(&self) -> TransactionType {
+        match self {
+            Transaction::Deposit(_) => TransactionType::Deposit,
+            Transaction::Withdrawal(_) => TransactionType::Withdrawal,
+            Transaction::Dispute(_) => TransactionType::Dispute,
+            Transaction::Resolve(_) => TransactionType::Resolve,
+            Transaction::Chargeback(_) => TransactionType::Chargeback,
+        }
+    }
+}
+
 /// Only a limited set of transactions is disputable
 //
-/// In the requirements, the business logic for disputes is only defined for deposits.
+/// In the requirements, the business logic for disputes is only defined for deposits. Withdrawals
+/// are only disputable with `HandlerConfig::disputable_withdrawals`.
 #[derive(Debug, Clone, PartialEq)]
 pub enum DisputableTransaction {
     Deposit(MonetaryTransactionRecord),
+    Withdrawal(MonetaryTransactionRecord),
+}
+
+impl DisputableTransaction {
+    /// The record of the transaction, no matter its kind
+    pub fn record(&self) -> &MonetaryTransactionRecord {
+        match self {
+            DisputableTransaction::Deposit(record) | DisputableTransaction::Withdrawal(record) => {
+                record
+            }
+        }
+    }
+}
+
+/// The latest state of a transaction that has been disputed at least once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisputeStatus {
+    /// The dispute is still open, funds are held
+    Disputed,
+
+    /// The last dispute was resolved, the transaction stands as before
+    Resolved,
+
+    /// The transaction was charged back
+    ChargedBack,
+}
+
+/// Reports how disputes regarding a transaction ended (so far)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisputeOutcome {
+    pub client: ClientId,
+    pub transaction: TransactionId,
+    pub status: DisputeStatus,
+}
+
+/// A transaction retained for potential disputes, e.g. for an audit
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredTransaction {
+    pub transaction: TransactionId,
+    pub client: ClientId,
+    pub amount: Amount,
+
+    /// `None` if the transaction has never been disputed
+    pub status: Option<DisputeStatus>,
+}
+
+/// Add two amounts, `None` if the sum does not fit
+#[cfg(not(feature = "float-amount"))]
+fn checked_sum(left: Amount, right: Amount) -> Option<Amount> {
+    left.checked_add(right)
+}
+
+/// Add two amounts, `None` if the sum is not finite
+#[cfg(feature = "float-amount")]
+fn checked_sum(left: Amount, right: Amount) -> Option<Amount> {
+    Some(left + right).filter(|sum| sum.is_finite())
 }
 
 /// Represents the current funds (available and held) of a client
@@ -50,25 +443,188 @@ pub struct Account {
 
 impl Account {
     /// Compute the total funds of the client (available and held)
+    ///
+    /// Held funds are only ever due to open disputes, there are no pending (two-phase)
+    /// authorizations that would need to be counted separately.
     pub fn total(&self) -> Amount {
         self.available + self.held
     }
+
+    /// Compute the total funds like `total`, but `None` instead of a panic if they overflow
+    pub fn checked_total(&self) -> Option<Amount> {
+        checked_sum(self.available, self.held)
+    }
+
+    /// Fraction of the total funds that is held, `None` if the total is zero
+    pub fn held_ratio(&self) -> Option<Amount> {
+        let total = self.total();
+        if total == Amount::default() {
+            None
+        } else {
+            Some(self.held / total)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::tx;
 
-    use rust_decimal_macros::dec;
+    use crate::test_support::amount;
 
     #[test]
     fn test_total() {
         let account = Account {
             client: 0,
-            available: dec!(1.0),
-            held: dec!(2.0),
+            available: amount!(1.0),
+            held: amount!(2.0),
             locked: false,
         };
-        assert_eq!(account.total(), dec!(3.0));
+        assert_eq!(account.total(), amount!(3.0));
+        assert_eq!(account.checked_total(), Some(amount!(3.0)));
+
+        let overflowing = Account {
+            available: Amount::MAX,
+            held: Amount::MAX,
+            ..account
+        };
+        assert_eq!(overflowing.checked_total(), None);
+    }
+
+    #[test]
+    fn held_ratio() {
+        let account = |available, held| Account {
+            client: 0,
+            available,
+            held,
+            locked: false,
+        };
+        assert_eq!(
+            account(amount!(0), amount!(2.5)).held_ratio(),
+            Some(amount!(1))
+        );
+        assert_eq!(
+            account(amount!(3), amount!(1)).held_ratio(),
+            Some(amount!(0.25))
+        );
+        assert_eq!(account(amount!(0), amount!(0)).held_ratio(), None);
+    }
+
+    fn record(amount: Amount) -> MonetaryTransactionRecord {
+        MonetaryTransactionRecord {
+            client: 0,
+            transaction: tx(7),
+            amount,
+        }
+    }
+
+    #[test]
+    fn validate_default_accepts_everything() {
+        let config = ValidationConfig::default();
+        record(amount!(-1.23456)).validate(&config).unwrap();
+        record(amount!(1000000000)).validate(&config).unwrap();
+    }
+
+    #[test]
+    fn validate_success() {
+        let config = ValidationConfig {
+            reject_negative: true,
+            max_scale: Some(4),
+            max_amount: Some(amount!(1000)),
+        };
+        record(amount!(0)).validate(&config).unwrap();
+        record(amount!(1.2345)).validate(&config).unwrap();
+        record(amount!(1.234500)).validate(&config).unwrap();
+        record(amount!(1000)).validate(&config).unwrap();
+    }
+
+    #[test]
+    fn validate_negative() {
+        let config = ValidationConfig {
+            reject_negative: true,
+            ..ValidationConfig::default()
+        };
+        assert_eq!(
+            record(amount!(-0.5)).validate(&config),
+            Err(TransactionError::NegativeAmount { transaction: tx(7) })
+        );
+    }
+
+    #[test]
+    fn validate_scale() {
+        let config = ValidationConfig {
+            max_scale: Some(4),
+            ..ValidationConfig::default()
+        };
+        assert_eq!(
+            record(amount!(1.23456)).validate(&config),
+            Err(TransactionError::ExcessiveScale {
+                transaction: tx(7),
+                max_scale: 4
+            })
+        );
+    }
+
+    #[test]
+    fn validate_range() {
+        let config = ValidationConfig {
+            max_amount: Some(amount!(1000)),
+            ..ValidationConfig::default()
+        };
+        assert_eq!(
+            record(amount!(1000.01)).validate(&config),
+            Err(TransactionError::AmountOutOfRange {
+                transaction: tx(7),
+                max_amount: amount!(1000)
+            })
+        );
+    }
+
+    #[test]
+    fn validated_constructors() {
+        assert_eq!(
+            Transaction::deposit(1, tx(7), amount!(1.2345)),
+            Ok(Transaction::Deposit(MonetaryTransactionRecord {
+                client: 1,
+                transaction: tx(7),
+                amount: amount!(1.2345),
+            }))
+        );
+        assert_eq!(
+            Transaction::withdrawal(1, tx(8), amount!(0.5))
+                .map(|transaction| transaction.transaction_type()),
+            Ok(TransactionType::Withdrawal)
+        );
+
+        assert_eq!(
+            Transaction::withdrawal(1, tx(8), amount!(0)),
+            Err(TransactionError::ZeroAmount { transaction: tx(8) })
+        );
+        assert_eq!(
+            Transaction::deposit(1, tx(7), amount!(0.0000)),
+            Err(TransactionError::ZeroAmount { transaction: tx(7) })
+        );
+
+        assert_eq!(
+            Transaction::deposit(1, tx(7), amount!(-1)),
+            Err(TransactionError::NegativeAmount { transaction: tx(7) })
+        );
+        assert_eq!(
+            Transaction::withdrawal(1, tx(8), amount!(0.00001)),
+            Err(TransactionError::ExcessiveScale {
+                transaction: tx(8),
+                max_scale: 4
+            })
+        );
+    }
+
+    #[cfg(feature = "string-tx-id")]
+    #[test]
+    fn string_id_order() {
+        let id = |text: &str| text.parse::<StringId>().unwrap();
+        assert!(id("ab") < id("b"));
+        assert!(id("10") < id("9"));
+        assert!(id("a") < id("ab"));
     }
 }